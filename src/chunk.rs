@@ -2,10 +2,23 @@ use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::rc::Rc;
 
-use crate::object::Object;
+use crate::memory::Gc;
+use crate::object::{Function, Object};
 use crate::value::Value;
 
+mod asm;
 mod debug;
+mod json;
+mod serialize;
+mod verify;
+
+pub use serialize::{LoadError, MAGIC};
+pub use verify::{VerifyError, VerifyErrorKind};
+
+/// How deeply function constants may nest. Reading and verifying bytecode
+/// recurse once per level, so this keeps hostile code from overflowing the
+/// stack.
+const MAX_FUNCTION_DEPTH: usize = 256;
 
 #[derive(Debug)]
 pub enum Op {
@@ -25,14 +38,43 @@ pub enum Op {
     Less,
     Print,
     Pop,
-    DefineGlobal { name: Rc<String> },
-    GetGlobal { name: Rc<String> },
-    SetGlobal { name: Rc<String> },
+    DefineGlobal { name: Gc<String> },
+    GetGlobal { name: Gc<String> },
+    SetGlobal { name: Gc<String> },
     GetLocal { idx: u8 },
     SetLocal { idx: u8 },
     JumpIfFalse { offset: u16 },
     Jump { offset: u16 },
     Loop { offset: u16 },
+    Call { arg_count: u8 },
+    Closure {
+        function: Gc<Function>,
+        upvalues: Vec<UpvalueIndex>,
+    },
+    GetUpvalue { idx: u8 },
+    SetUpvalue { idx: u8 },
+    CloseUpvalue,
+    Class { name: Gc<String> },
+    GetProperty { name: Gc<String> },
+    SetProperty { name: Gc<String> },
+    Method { name: Gc<String> },
+    Inherit,
+    GetSuper { name: Gc<String> },
+    BuildList { item_count: u8 },
+    BuildMap { entry_count: u8 },
+    GetIndex,
+    SetIndex,
+    GetIter,
+    ForIter { offset: u16 },
+}
+
+/// Where a closure finds a captured variable when it is created: either a
+/// local slot of the enclosing function or one of the enclosing closure's own
+/// upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueIndex {
+    pub is_local: bool,
+    pub index: u8,
 }
 
 #[derive(Debug)]
@@ -65,6 +107,29 @@ enum OpCode {
     JumpIfFalse,
     Jump,
     Loop,
+    Call,
+    Closure,
+    ClosureLong,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    ClassLong,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    Method,
+    MethodLong,
+    Inherit,
+    GetSuper,
+    GetSuperLong,
+    BuildList,
+    GetIndex,
+    SetIndex,
+    BuildMap,
+    GetIter,
+    ForIter,
 }
 
 impl OpCode {
@@ -110,6 +175,29 @@ impl TryFrom<u8> for OpCode {
             25 => Ok(OpCode::JumpIfFalse),
             26 => Ok(OpCode::Jump),
             27 => Ok(OpCode::Loop),
+            28 => Ok(OpCode::Call),
+            29 => Ok(OpCode::Closure),
+            30 => Ok(OpCode::ClosureLong),
+            31 => Ok(OpCode::GetUpvalue),
+            32 => Ok(OpCode::SetUpvalue),
+            33 => Ok(OpCode::CloseUpvalue),
+            34 => Ok(OpCode::Class),
+            35 => Ok(OpCode::ClassLong),
+            36 => Ok(OpCode::GetProperty),
+            37 => Ok(OpCode::GetPropertyLong),
+            38 => Ok(OpCode::SetProperty),
+            39 => Ok(OpCode::SetPropertyLong),
+            40 => Ok(OpCode::Method),
+            41 => Ok(OpCode::MethodLong),
+            42 => Ok(OpCode::Inherit),
+            43 => Ok(OpCode::GetSuper),
+            44 => Ok(OpCode::GetSuperLong),
+            45 => Ok(OpCode::BuildList),
+            46 => Ok(OpCode::GetIndex),
+            47 => Ok(OpCode::SetIndex),
+            48 => Ok(OpCode::BuildMap),
+            49 => Ok(OpCode::GetIter),
+            50 => Ok(OpCode::ForIter),
             _ => Err(()),
         }
     }
@@ -120,6 +208,12 @@ pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
     pub line_nos: Vec<(u32, u32)>,
+    /// Column and width, in characters, of the token each instruction was
+    /// compiled from. Indexed by instruction rather than by byte.
+    pub columns: Vec<(usize, usize)>,
+    /// The source the chunk was compiled from, used to show the offending
+    /// line when reporting an error.
+    pub source: Rc<str>,
 }
 
 impl Chunk {
@@ -128,10 +222,12 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             line_nos: vec![],
+            columns: vec![],
+            source: Rc::from(""),
         }
     }
 
-    pub fn push_op_code(&mut self, op: Op, line_no: u32) {
+    pub fn push_op_code(&mut self, op: Op, line_no: u32, column: usize, width: usize) {
         match op {
             Op::Return => self.code.push(0),
             Op::Constant { value } => self.push_constant_op(value, 1, 2),
@@ -178,22 +274,74 @@ impl Chunk {
                 self.code.push(27);
                 self.push_u16(offset);
             }
+            Op::Call { arg_count } => {
+                self.code.push(28);
+                self.code.push(arg_count);
+            }
+            Op::Closure { function, upvalues } => {
+                self.push_constant_op(Value::Obj(Object::Function(function)), 29, 30);
+                for upvalue in upvalues {
+                    self.code.push(upvalue.is_local as u8);
+                    self.code.push(upvalue.index);
+                }
+            }
+            Op::GetUpvalue { idx } => {
+                self.code.push(31);
+                self.code.push(idx);
+            }
+            Op::SetUpvalue { idx } => {
+                self.code.push(32);
+                self.code.push(idx);
+            }
+            Op::CloseUpvalue => self.code.push(33),
+            Op::Class { name } => {
+                self.push_constant_op(Value::Obj(Object::String { chars: name }), 34, 35)
+            }
+            Op::GetProperty { name } => {
+                self.push_constant_op(Value::Obj(Object::String { chars: name }), 36, 37)
+            }
+            Op::SetProperty { name } => {
+                self.push_constant_op(Value::Obj(Object::String { chars: name }), 38, 39)
+            }
+            Op::Method { name } => {
+                self.push_constant_op(Value::Obj(Object::String { chars: name }), 40, 41)
+            }
+            Op::Inherit => self.code.push(42),
+            Op::GetSuper { name } => {
+                self.push_constant_op(Value::Obj(Object::String { chars: name }), 43, 44)
+            }
+            Op::BuildList { item_count } => {
+                self.code.push(45);
+                self.code.push(item_count);
+            }
+            Op::GetIndex => self.code.push(46),
+            Op::SetIndex => self.code.push(47),
+            Op::BuildMap { entry_count } => {
+                self.code.push(48);
+                self.code.push(entry_count);
+            }
+            Op::GetIter => self.code.push(49),
+            Op::ForIter { offset } => {
+                self.code.push(50);
+                self.push_u16(offset);
+            }
         }
         self.push_line_no(line_no);
+        self.columns.push((column, width));
     }
 
     fn push_constant_op(&mut self, value: Value, short_op_code: u8, long_op_code: u8) {
         self.constants.push(value);
-        const U8_SIZE: usize = ::std::mem::size_of::<u8>() * 8;
-        const U8_SIZE_PLUS_1: usize = ::std::mem::size_of::<u8>() * 8 + 1;
-        const U16_SIZE: usize = ::std::mem::size_of::<u16>() * 8;
+        const U8_MAX: usize = u8::MAX as usize;
+        const U8_MAX_PLUS_1: usize = u8::MAX as usize + 1;
+        const U16_MAX: usize = u16::MAX as usize;
         let const_idx = self.constants.len() - 1;
         match const_idx {
-            0..=U8_SIZE => {
+            0..=U8_MAX => {
                 self.code.push(short_op_code);
                 self.code.push(const_idx as u8);
             }
-            U8_SIZE_PLUS_1..=U16_SIZE => {
+            U8_MAX_PLUS_1..=U16_MAX => {
                 self.code.push(long_op_code);
                 self.push_u16(const_idx as u16);
             }
@@ -310,17 +458,152 @@ impl Chunk {
                 },
                 3,
             ),
+            OpCode::Call => (
+                Op::Call {
+                    arg_count: self.code[idx + 1],
+                },
+                2,
+            ),
+            OpCode::Closure => self.decode_closure(idx, self.get_const_short(idx), 2),
+            OpCode::ClosureLong => self.decode_closure(idx, self.get_const_long(idx), 3),
+            OpCode::GetUpvalue => (
+                Op::GetUpvalue {
+                    idx: self.code[idx + 1],
+                },
+                2,
+            ),
+            OpCode::SetUpvalue => (
+                Op::SetUpvalue {
+                    idx: self.code[idx + 1],
+                },
+                2,
+            ),
+            OpCode::CloseUpvalue => (Op::CloseUpvalue, 1),
+            OpCode::Class => (
+                Op::Class {
+                    name: self.get_name_short(idx),
+                },
+                2,
+            ),
+            OpCode::ClassLong => (
+                Op::Class {
+                    name: self.get_name_long(idx),
+                },
+                3,
+            ),
+            OpCode::GetProperty => (
+                Op::GetProperty {
+                    name: self.get_name_short(idx),
+                },
+                2,
+            ),
+            OpCode::GetPropertyLong => (
+                Op::GetProperty {
+                    name: self.get_name_long(idx),
+                },
+                3,
+            ),
+            OpCode::SetProperty => (
+                Op::SetProperty {
+                    name: self.get_name_short(idx),
+                },
+                2,
+            ),
+            OpCode::SetPropertyLong => (
+                Op::SetProperty {
+                    name: self.get_name_long(idx),
+                },
+                3,
+            ),
+            OpCode::Method => (
+                Op::Method {
+                    name: self.get_name_short(idx),
+                },
+                2,
+            ),
+            OpCode::MethodLong => (
+                Op::Method {
+                    name: self.get_name_long(idx),
+                },
+                3,
+            ),
+            OpCode::Inherit => (Op::Inherit, 1),
+            OpCode::GetSuper => (
+                Op::GetSuper {
+                    name: self.get_name_short(idx),
+                },
+                2,
+            ),
+            OpCode::GetSuperLong => (
+                Op::GetSuper {
+                    name: self.get_name_long(idx),
+                },
+                3,
+            ),
+            OpCode::BuildList => (
+                Op::BuildList {
+                    item_count: self.code[idx + 1],
+                },
+                2,
+            ),
+            OpCode::GetIndex => (Op::GetIndex, 1),
+            OpCode::SetIndex => (Op::SetIndex, 1),
+            OpCode::BuildMap => (
+                Op::BuildMap {
+                    entry_count: self.code[idx + 1],
+                },
+                2,
+            ),
+            OpCode::GetIter => (Op::GetIter, 1),
+            OpCode::ForIter => (
+                Op::ForIter {
+                    offset: self.get_u16(idx + 1),
+                },
+                3,
+            ),
         }
     }
 
+    fn decode_closure(&self, idx: usize, value: Value, const_size: usize) -> (Op, usize) {
+        let function = match value {
+            Value::Obj(Object::Function(function)) => function,
+            _ => panic!("Expected function object value!"),
+        };
+        let upvalues = (0..function.upvalue_count)
+            .map(|i| {
+                let upvalue_idx = idx + const_size + 2 * i;
+                UpvalueIndex {
+                    is_local: self.code[upvalue_idx] == 1,
+                    index: self.code[upvalue_idx + 1],
+                }
+            })
+            .collect::<Vec<_>>();
+        let size = const_size + 2 * upvalues.len();
+        (Op::Closure { function, upvalues }, size)
+    }
+
     fn get_const_short(&self, idx: usize) -> Value {
         let const_idx = self.code[idx + 1];
-        self.constants[const_idx as usize].clone()
+        self.constants[const_idx as usize]
     }
 
     fn get_const_long(&self, idx: usize) -> Value {
         let const_idx = self.get_u16(idx + 1);
-        self.constants[const_idx as usize].clone()
+        self.constants[const_idx as usize]
+    }
+
+    fn get_name_short(&self, idx: usize) -> Gc<String> {
+        match self.get_const_short(idx) {
+            Value::Obj(Object::String { chars: name }) => name,
+            _ => panic!("Expected string object value!"),
+        }
+    }
+
+    fn get_name_long(&self, idx: usize) -> Gc<String> {
+        match self.get_const_long(idx) {
+            Value::Obj(Object::String { chars: name }) => name,
+            _ => panic!("Expected string object value!"),
+        }
     }
 
     fn get_u16(&self, idx: usize) -> u16 {
@@ -344,13 +627,21 @@ impl Chunk {
         );
     }
 
+    /// Column and width of the token instruction `op_idx` was compiled from.
+    pub fn get_column(&self, op_idx: usize) -> (usize, usize) {
+        self.columns[op_idx]
+    }
+
+    /// Index of the instruction containing the byte at `code_idx`.
     pub fn get_op_idx(&self, code_idx: usize) -> usize {
         let mut i = 0;
         let mut result = 0;
-        while i < code_idx {
+        loop {
             i += self.decode(i).1;
-            result += 0
+            if i > code_idx {
+                return result;
+            }
+            result += 1;
         }
-        result
     }
 }
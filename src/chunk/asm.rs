@@ -0,0 +1,868 @@
+//! A textual assembly language for chunks, read by `Chunk::assemble` and
+//! written by `Chunk::to_assembly`.
+//!
+//! Each line holds one instruction, written with the disassembler's `OP_`
+//! names, or a directive. `;` starts a comment.
+//!
+//! ```text
+//!     .line 1                     ; following instructions are on line 1
+//!     OP_CONSTANT "hi"            ; nil, true, false, numbers or strings
+//!     OP_DEFINE_GLOBAL greeting   ; names may also be quoted
+//! top:                            ; a label, local to its function
+//!     OP_GET_GLOBAL greeting
+//!     OP_JUMP_IF_FALSE done       ; jumps and loops name a label
+//!     OP_LOOP top
+//! done:
+//!     OP_CLOSURE add 2 [local 1, upvalue 0] {
+//!         OP_GET_LOCAL 1          ; the nested function's body
+//!         OP_RETURN
+//!     }
+//!     OP_NIL
+//!     OP_RETURN
+//! ```
+//!
+//! The `_LONG` forms of instructions are picked automatically when a chunk
+//! has more than 256 constants. Columns are not part of the dialect, so
+//! assembled instructions have none.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use rstest::rstest;
+
+use super::debug::mnemonic;
+use super::{Chunk, Op, UpvalueIndex};
+#[cfg(test)]
+use crate::compiler;
+use crate::diagnostic::Diagnostic;
+use crate::memory::{Gc, Heap};
+use crate::object::{Function, Object};
+use crate::scanner;
+use crate::strings::Strings;
+use crate::value::Value;
+
+/// Characters that end a bare word.
+const SPECIAL: &str = ";\":{}[],";
+
+impl Chunk {
+    /// Assemble `text` into a script chunk. Like `read_from`, the chunk's
+    /// objects are allocated on `heap` but not rooted. The chunk is not
+    /// verified, so it may be rejected by `verify`.
+    pub fn assemble(
+        text: &str,
+        heap: &mut Heap,
+        strings: &mut Strings,
+    ) -> Result<Chunk, Vec<Diagnostic>> {
+        let mut assembler = Assembler {
+            heap,
+            strings,
+            functions: vec![Builder::new(None, 1)],
+            diagnostics: vec![],
+        };
+        let mut offset = 0;
+        for (line_no, line) in (1..).zip(text.split('\n')) {
+            let result = tokenize(line.trim_end_matches('\r'), line_no, offset)
+                .and_then(|tokens| assembler.line(&mut Tokens { tokens, pos: 0 }));
+            if let Err(diagnostic) = result {
+                assembler.diagnostics.push(diagnostic);
+            }
+            offset += line.len() + 1;
+        }
+        while assembler.functions.len() > 1 {
+            let header = assembler.functions.last().unwrap().header.as_ref().unwrap();
+            let message = format!("Expect '}}' to close '{}'.", header.name);
+            let diagnostic = header.token.error(message);
+            assembler.diagnostics.push(diagnostic);
+            assembler.end_function();
+        }
+        let script = assembler.functions.pop().unwrap();
+        let chunk = assembler.finish(script);
+        if assembler.diagnostics.is_empty() {
+            Ok(chunk)
+        } else {
+            Err(assembler.diagnostics)
+        }
+    }
+
+    /// This chunk, taken as a script, in the assembly dialect. Assembling
+    /// the result gives back the same chunk apart from its columns. Fails
+    /// if `OP_CONSTANT` loads an object other than a string, which only
+    /// hand-made bytecode can do and the dialect has no way to write.
+    pub fn to_assembly(&self) -> Result<String, String> {
+        let mut out = String::new();
+        self.write_assembly(&mut out, 0, &mut None)?;
+        Ok(out)
+    }
+
+    fn write_assembly(
+        &self,
+        out: &mut String,
+        depth: usize,
+        line: &mut Option<u32>,
+    ) -> Result<(), String> {
+        let indent = "    ".repeat(depth);
+        let mut ops = vec![];
+        let mut labels = BTreeMap::new();
+        let mut code_idx = 0;
+        while code_idx < self.code.len() {
+            let (op, size) = self.decode(code_idx);
+            let end = code_idx + size;
+            match op {
+                Op::JumpIfFalse { offset } | Op::Jump { offset } | Op::ForIter { offset } => {
+                    labels.insert(end + offset as usize, 0);
+                }
+                Op::Loop { offset } => {
+                    labels.insert(end - offset as usize, 0);
+                }
+                _ => {}
+            }
+            ops.push((code_idx, op));
+            code_idx = end;
+        }
+        for (n, label) in labels.values_mut().enumerate() {
+            *label = n;
+        }
+
+        for (op_idx, (code_idx, op)) in ops.into_iter().enumerate() {
+            if let Some(label) = labels.get(&code_idx) {
+                out.push_str(&format!("{indent}L{label}:\n"));
+            }
+            let line_no = self.get_line_no(op_idx);
+            if *line != Some(line_no) {
+                out.push_str(&format!("{indent}    .line {line_no}\n"));
+                *line = Some(line_no);
+            }
+            let end = code_idx + self.decode(code_idx).1;
+            let operand = match &op {
+                Op::Constant { value } => Some(literal(value)?),
+                Op::DefineGlobal { name }
+                | Op::GetGlobal { name }
+                | Op::SetGlobal { name }
+                | Op::Class { name }
+                | Op::GetProperty { name }
+                | Op::SetProperty { name }
+                | Op::Method { name }
+                | Op::GetSuper { name } => Some(word(name)),
+                Op::GetLocal { idx }
+                | Op::SetLocal { idx }
+                | Op::GetUpvalue { idx }
+                | Op::SetUpvalue { idx } => Some(idx.to_string()),
+                Op::Call { arg_count } => Some(arg_count.to_string()),
+                Op::BuildList { item_count } => Some(item_count.to_string()),
+                Op::BuildMap { entry_count } => Some(entry_count.to_string()),
+                Op::JumpIfFalse { offset } | Op::Jump { offset } | Op::ForIter { offset } => {
+                    Some(format!("L{}", labels[&(end + *offset as usize)]))
+                }
+                Op::Loop { offset } => Some(format!("L{}", labels[&(end - *offset as usize)])),
+                Op::Closure { function, upvalues } => {
+                    let upvalues: Vec<String> = upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                            format!("{kind} {}", upvalue.index)
+                        })
+                        .collect();
+                    let name = function.name.as_deref().unwrap_or("");
+                    out.push_str(&format!(
+                        "{indent}    {} {} {} [{}] {{\n",
+                        mnemonic(&op),
+                        word(name),
+                        function.arity,
+                        upvalues.join(", ")
+                    ));
+                    function
+                        .chunk
+                        .write_assembly(out, depth + 1, &mut line.clone())?;
+                    out.push_str(&format!("{indent}    }}\n"));
+                    continue;
+                }
+                _ => None,
+            };
+            match operand {
+                Some(operand) => {
+                    out.push_str(&format!("{indent}    {} {operand}\n", mnemonic(&op)))
+                }
+                None => out.push_str(&format!("{indent}    {}\n", mnemonic(&op))),
+            }
+        }
+        if let Some(label) = labels.get(&self.code.len()) {
+            out.push_str(&format!("{indent}L{label}:\n"));
+        }
+        Ok(())
+    }
+}
+
+fn literal(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Nil => Ok("nil".to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Obj(Object::String { chars }) => Ok(quote(chars)),
+        Value::Obj(object) => Err(format!("Can't write constant {} as assembly.", object)),
+    }
+}
+
+/// `string` as a bare word if it can be read back as one, otherwise quoted.
+fn word(string: &str) -> String {
+    let bare = !string.is_empty()
+        && !string.starts_with('.')
+        && !string
+            .chars()
+            .any(|c| c.is_whitespace() || SPECIAL.contains(c));
+    if bare {
+        string.to_string()
+    } else {
+        quote(string)
+    }
+}
+
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Str(String),
+    Punct(char),
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    line: u32,
+    column: usize,
+    span: Range<usize>,
+}
+
+impl Token {
+    fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::error(self.line, self.column, self.span.clone(), message)
+    }
+}
+
+/// Split one line, starting at byte `offset` of the text, into tokens
+/// ending with `TokenKind::End`.
+fn tokenize(line: &str, line_no: u32, offset: usize) -> Result<Vec<Token>, Diagnostic> {
+    let make_token = |kind, start: usize, end: usize| Token {
+        kind,
+        line: line_no,
+        column: line[..start].chars().count() + 1,
+        span: offset + start..offset + end,
+    };
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+        if c == '"' {
+            // Find the closing quote, then read escapes as Lox strings do.
+            let end = loop {
+                match chars.next() {
+                    Some((end, '"')) => break end + 1,
+                    Some((_, '\\')) => {
+                        if chars.next().is_none() {
+                            break line.len() + 1;
+                        }
+                    }
+                    Some(_) => {}
+                    None => break line.len() + 1,
+                }
+            };
+            if end > line.len() {
+                let token = make_token(TokenKind::End, start, line.len());
+                return Err(token.error("Unterminated string.".to_string()));
+            }
+            match scanner::unescape(&line[start + 1..end - 1]) {
+                Ok(string) => tokens.push(make_token(TokenKind::Str(string), start, end)),
+                Err((range, message)) => {
+                    let body = start + 1;
+                    let token = make_token(TokenKind::End, body + range.start, body + range.end);
+                    return Err(token.error(message));
+                }
+            }
+        } else if SPECIAL.contains(c) {
+            tokens.push(make_token(TokenKind::Punct(c), start, start + 1));
+        } else {
+            let mut end = start + c.len_utf8();
+            while let Some(&(next, c)) = chars.peek() {
+                if c.is_whitespace() || SPECIAL.contains(c) {
+                    break;
+                }
+                end = next + c.len_utf8();
+                chars.next();
+            }
+            let word = line[start..end].to_string();
+            tokens.push(make_token(TokenKind::Word(word), start, end));
+        }
+    }
+    tokens.push(make_token(TokenKind::End, line.len(), line.len()));
+    Ok(tokens)
+}
+
+struct Tokens {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Tokens {
+    fn peek(&self, n: usize) -> &TokenKind {
+        let idx = (self.pos + n).min(self.tokens.len() - 1);
+        &self.tokens[idx].kind
+    }
+
+    /// The next token, staying on `End` once it is reached.
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, punct: char, message: &str) -> Result<(), Diagnostic> {
+        let token = self.next();
+        if token.kind == TokenKind::Punct(punct) {
+            Ok(())
+        } else {
+            Err(token.error(message.to_string()))
+        }
+    }
+
+    fn word(&mut self, message: &str) -> Result<(String, Token), Diagnostic> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Word(word) => Ok((word.clone(), token)),
+            _ => Err(token.error(message.to_string())),
+        }
+    }
+
+    /// A bare or quoted word.
+    fn name(&mut self, message: &str) -> Result<String, Diagnostic> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Word(word) | TokenKind::Str(word) => Ok(word),
+            _ => Err(token.error(message.to_string())),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, message: &str) -> Result<T, Diagnostic> {
+        let (word, token) = self.word(message)?;
+        word.parse().map_err(|_| token.error(message.to_string()))
+    }
+}
+
+/// The header of a function being assembled, from its `OP_CLOSURE`.
+struct Header {
+    name: String,
+    arity: usize,
+    upvalues: Vec<UpvalueIndex>,
+    token: Token,
+}
+
+/// A jump whose offset is filled in once its function is complete.
+struct Jump {
+    /// Index of the jump's operand in the code.
+    operand: usize,
+    backwards: bool,
+    label: String,
+    token: Token,
+}
+
+struct Builder {
+    /// `None` for the script.
+    header: Option<Header>,
+    chunk: Chunk,
+    line: u32,
+    labels: HashMap<String, usize>,
+    jumps: Vec<Jump>,
+}
+
+impl Builder {
+    fn new(header: Option<Header>, line: u32) -> Self {
+        Builder {
+            header,
+            chunk: Chunk::new(),
+            line,
+            labels: HashMap::new(),
+            jumps: vec![],
+        }
+    }
+}
+
+struct Assembler<'a> {
+    heap: &'a mut Heap,
+    strings: &'a mut Strings,
+    /// Functions being assembled, innermost last. The first is the script.
+    functions: Vec<Builder>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Assembler<'a> {
+    fn current(&mut self) -> &mut Builder {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn line(&mut self, tokens: &mut Tokens) -> Result<(), Diagnostic> {
+        if let (TokenKind::Word(_), TokenKind::Punct(':')) = (tokens.peek(0), tokens.peek(1)) {
+            let (label, token) = tokens.word("Expect label.")?;
+            tokens.next();
+            let code_idx = self.current().chunk.code.len();
+            if self
+                .current()
+                .labels
+                .insert(label.clone(), code_idx)
+                .is_some()
+            {
+                return Err(token.error(format!("Label '{label}' is already defined.")));
+            }
+        }
+        let token = tokens.next();
+        match &token.kind {
+            TokenKind::End => return Ok(()),
+            TokenKind::Punct('}') => {
+                if self.functions.len() == 1 {
+                    return Err(token.error("Unmatched '}'.".to_string()));
+                }
+                self.end_function();
+            }
+            TokenKind::Word(directive) if directive == ".line" => {
+                self.current().line = tokens.number("Expect line number.")?;
+            }
+            TokenKind::Word(directive) if directive.starts_with('.') => {
+                return Err(token.error(format!("Unknown directive '{directive}'.")));
+            }
+            TokenKind::Word(mnemonic) => self.instruction(mnemonic, &token, tokens)?,
+            _ => return Err(token.error("Expect instruction.".to_string())),
+        }
+        let token = tokens.next();
+        if token.kind != TokenKind::End {
+            return Err(token.error("Expect end of line.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        mnemonic: &str,
+        token: &Token,
+        tokens: &mut Tokens,
+    ) -> Result<(), Diagnostic> {
+        let op = match mnemonic {
+            "OP_RETURN" => Op::Return,
+            "OP_CONSTANT" => Op::Constant {
+                value: self.literal(tokens)?,
+            },
+            "OP_NIL" => Op::Nil,
+            "OP_FALSE" => Op::False,
+            "OP_TRUE" => Op::True,
+            "OP_NEGATE" => Op::Negate,
+            "OP_ADD" => Op::Add,
+            "OP_SUBTRACT" => Op::Subtract,
+            "OP_MULTIPLY" => Op::Multiply,
+            "OP_DIVIDE" => Op::Divide,
+            "OP_NOT" => Op::Not,
+            "OP_EQUAL" => Op::Equal,
+            "OP_GREATER" => Op::Greater,
+            "OP_LESS" => Op::Less,
+            "OP_PRINT" => Op::Print,
+            "OP_POP" => Op::Pop,
+            "OP_DEFINE_GLOBAL" => Op::DefineGlobal {
+                name: self.name(tokens)?,
+            },
+            "OP_GET_GLOBAL" => Op::GetGlobal {
+                name: self.name(tokens)?,
+            },
+            "OP_SET_GLOBAL" => Op::SetGlobal {
+                name: self.name(tokens)?,
+            },
+            "OP_GET_LOCAL" => Op::GetLocal {
+                idx: tokens.number("Expect a slot from 0 to 255.")?,
+            },
+            "OP_SET_LOCAL" => Op::SetLocal {
+                idx: tokens.number("Expect a slot from 0 to 255.")?,
+            },
+            "OP_JUMP_IF_FALSE" => self.jump(tokens, false, |offset| Op::JumpIfFalse { offset })?,
+            "OP_JUMP" => self.jump(tokens, false, |offset| Op::Jump { offset })?,
+            "OP_LOOP" => self.jump(tokens, true, |offset| Op::Loop { offset })?,
+            "OP_CALL" => Op::Call {
+                arg_count: tokens.number("Expect an argument count from 0 to 255.")?,
+            },
+            "OP_CLOSURE" => return self.begin_function(token, tokens),
+            "OP_GET_UPVALUE" => Op::GetUpvalue {
+                idx: tokens.number("Expect an upvalue from 0 to 255.")?,
+            },
+            "OP_SET_UPVALUE" => Op::SetUpvalue {
+                idx: tokens.number("Expect an upvalue from 0 to 255.")?,
+            },
+            "OP_CLOSE_UPVALUE" => Op::CloseUpvalue,
+            "OP_CLASS" => Op::Class {
+                name: self.name(tokens)?,
+            },
+            "OP_GET_PROPERTY" => Op::GetProperty {
+                name: self.name(tokens)?,
+            },
+            "OP_SET_PROPERTY" => Op::SetProperty {
+                name: self.name(tokens)?,
+            },
+            "OP_METHOD" => Op::Method {
+                name: self.name(tokens)?,
+            },
+            "OP_INHERIT" => Op::Inherit,
+            "OP_GET_SUPER" => Op::GetSuper {
+                name: self.name(tokens)?,
+            },
+            "OP_BUILD_LIST" => Op::BuildList {
+                item_count: tokens.number("Expect an item count from 0 to 255.")?,
+            },
+            "OP_BUILD_MAP" => Op::BuildMap {
+                entry_count: tokens.number("Expect an entry count from 0 to 255.")?,
+            },
+            "OP_GET_INDEX" => Op::GetIndex,
+            "OP_SET_INDEX" => Op::SetIndex,
+            "OP_GET_ITER" => Op::GetIter,
+            "OP_FOR_ITER" => self.jump(tokens, false, |offset| Op::ForIter { offset })?,
+            _ => return Err(token.error(format!("Unknown instruction '{mnemonic}'."))),
+        };
+        self.emit(op, token)
+    }
+
+    fn emit(&mut self, op: Op, token: &Token) -> Result<(), Diagnostic> {
+        let has_constant = !matches!(
+            op,
+            Op::Return
+                | Op::Nil
+                | Op::False
+                | Op::True
+                | Op::Negate
+                | Op::Add
+                | Op::Subtract
+                | Op::Multiply
+                | Op::Divide
+                | Op::Not
+                | Op::Equal
+                | Op::Greater
+                | Op::Less
+                | Op::Print
+                | Op::Pop
+                | Op::GetLocal { .. }
+                | Op::SetLocal { .. }
+                | Op::JumpIfFalse { .. }
+                | Op::Jump { .. }
+                | Op::Loop { .. }
+                | Op::Call { .. }
+                | Op::GetUpvalue { .. }
+                | Op::SetUpvalue { .. }
+                | Op::CloseUpvalue
+                | Op::Inherit
+                | Op::BuildList { .. }
+                | Op::BuildMap { .. }
+                | Op::GetIndex
+                | Op::SetIndex
+                | Op::GetIter
+                | Op::ForIter { .. }
+        );
+        let current = self.current();
+        if has_constant && current.chunk.constants.len() > u16::MAX as usize {
+            return Err(token.error("Too many constants in one chunk.".to_string()));
+        }
+        let line = current.line;
+        current.chunk.push_op_code(op, line, 0, 0);
+        Ok(())
+    }
+
+    fn literal(&mut self, tokens: &mut Tokens) -> Result<Value, Diagnostic> {
+        let token = tokens.next();
+        match token.kind {
+            TokenKind::Str(string) => {
+                let chars = self.strings.new_string(self.heap, string);
+                Ok(Value::Obj(Object::String { chars }))
+            }
+            TokenKind::Word(word) if word == "nil" => Ok(Value::Nil),
+            TokenKind::Word(word) if word == "true" => Ok(Value::Bool(true)),
+            TokenKind::Word(word) if word == "false" => Ok(Value::Bool(false)),
+            TokenKind::Word(ref word) => match word.parse() {
+                Ok(n) => Ok(Value::Number(n)),
+                Err(_) => Err(token.error("Expect constant value.".to_string())),
+            },
+            _ => Err(token.error("Expect constant value.".to_string())),
+        }
+    }
+
+    fn name(&mut self, tokens: &mut Tokens) -> Result<Gc<String>, Diagnostic> {
+        let name = tokens.name("Expect name.")?;
+        Ok(self.strings.new_string(self.heap, name))
+    }
+
+    /// Emit a jump to a label, patched by `finish`.
+    fn jump(
+        &mut self,
+        tokens: &mut Tokens,
+        backwards: bool,
+        make_op: fn(u16) -> Op,
+    ) -> Result<Op, Diagnostic> {
+        let (label, token) = tokens.word("Expect label.")?;
+        let operand = self.current().chunk.code.len() + 1;
+        self.current().jumps.push(Jump {
+            operand,
+            backwards,
+            label,
+            token,
+        });
+        Ok(make_op(0))
+    }
+
+    fn begin_function(&mut self, token: &Token, tokens: &mut Tokens) -> Result<(), Diagnostic> {
+        let name = tokens.name("Expect function name.")?;
+        let arity: u8 = tokens.number("Expect an arity from 0 to 255.")?;
+        tokens.expect('[', "Expect '[' before captured variables.")?;
+        let mut upvalues = vec![];
+        if *tokens.peek(0) != TokenKind::Punct(']') {
+            loop {
+                let (kind, kind_token) = tokens.word("Expect 'local' or 'upvalue'.")?;
+                let is_local = match kind.as_str() {
+                    "local" => true,
+                    "upvalue" => false,
+                    _ => return Err(kind_token.error("Expect 'local' or 'upvalue'.".to_string())),
+                };
+                let index = tokens.number("Expect an index from 0 to 255.")?;
+                upvalues.push(UpvalueIndex { is_local, index });
+                if *tokens.peek(0) != TokenKind::Punct(',') {
+                    break;
+                }
+                tokens.next();
+            }
+        }
+        tokens.expect(']', "Expect ']' after captured variables.")?;
+        tokens.expect('{', "Expect '{' before function body.")?;
+        let line = self.current().line;
+        let header = Header {
+            name,
+            arity: arity as usize,
+            upvalues,
+            token: token.clone(),
+        };
+        self.functions.push(Builder::new(Some(header), line));
+        Ok(())
+    }
+
+    /// Finish the innermost function and emit the `OP_CLOSURE` creating it.
+    fn end_function(&mut self) {
+        let mut builder = self.functions.pop().unwrap();
+        let header = builder.header.take().unwrap();
+        let chunk = self.finish(builder);
+        let function = self.heap.alloc(Function {
+            arity: header.arity,
+            upvalue_count: header.upvalues.len(),
+            chunk,
+            name: Some(header.name),
+        });
+        let op = Op::Closure {
+            function,
+            upvalues: header.upvalues,
+        };
+        if let Err(diagnostic) = self.emit(op, &header.token) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// Patch the function's jumps now that all its labels are known.
+    fn finish(&mut self, builder: Builder) -> Chunk {
+        let mut chunk = builder.chunk;
+        for jump in builder.jumps {
+            let target = match builder.labels.get(&jump.label) {
+                Some(&target) => target,
+                None => {
+                    let message = format!("Undefined label '{}'.", jump.label);
+                    self.diagnostics.push(jump.token.error(message));
+                    continue;
+                }
+            };
+            let end = jump.operand + 2;
+            let offset = if jump.backwards {
+                end.checked_sub(target)
+            } else {
+                target.checked_sub(end)
+            };
+            let offset = match offset.map(u16::try_from) {
+                Some(Ok(offset)) => offset,
+                Some(Err(_)) => {
+                    let message = format!("Label '{}' is too far away.", jump.label);
+                    self.diagnostics.push(jump.token.error(message));
+                    continue;
+                }
+                None => {
+                    let direction = if jump.backwards { "after" } else { "before" };
+                    let message = format!("Label '{}' is {direction} the jump.", jump.label);
+                    self.diagnostics.push(jump.token.error(message));
+                    continue;
+                }
+            };
+            chunk.code[jump.operand..end].copy_from_slice(&offset.to_le_bytes());
+        }
+        chunk
+    }
+}
+
+#[cfg(test)]
+fn assemble(
+    text: &str,
+    heap: &mut Heap,
+    strings: &mut Strings,
+) -> Result<Chunk, Vec<(u32, usize, String)>> {
+    Chunk::assemble(text, heap, strings).map_err(|diagnostics| {
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message))
+            .collect()
+    })
+}
+
+#[rstest]
+#[case::arithmetic("print -(1 + 2) * 3 / 4 - 5;")]
+#[case::globals("var a = \"x; \\\\ {y}\"; a = a; print a;")]
+#[case::control_flow(
+    "for (var i = 0; i < 3; i = i + 1) { if (i == 1 and true) print i; else print nil; }"
+)]
+#[case::closures(
+    "fun f(a, b) { var c = a; fun g() { return a + b + c; } return g; } print f(1, 2)();"
+)]
+#[case::classes(
+    "class A { init(x) { this.x = x; } m() { return this.x; } }
+     class B < A { m() { return super.m() + 1; } }
+     print B(1).m();"
+)]
+fn assembly_round_trips(#[case] source: &str) {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let function = compiler::compile(source, &mut heap, &mut strings, &|_| {}, false).unwrap();
+    let text = function.chunk.to_assembly().unwrap();
+    let chunk = Chunk::assemble(&text, &mut heap, &mut strings).unwrap();
+    assert_eq!(chunk.code, function.chunk.code);
+    assert_eq!(chunk.line_nos, function.chunk.line_nos);
+    assert_eq!(chunk.to_assembly().unwrap(), text);
+    assert_eq!(chunk.verify(), Ok(()));
+}
+
+#[rstest]
+fn control_characters_are_escaped() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let source = r#"print "a\0b\u{7}\u{1b}[0m\té";"#;
+    let function = compiler::compile(source, &mut heap, &mut strings, &|_| {}, false).unwrap();
+    let text = function.chunk.to_assembly().unwrap();
+    assert!(text.contains(r#"OP_CONSTANT "a\0b\u{7}\u{1b}[0m\té""#), "{}", text);
+    let chunk = Chunk::assemble(&text, &mut heap, &mut strings).unwrap();
+    assert_eq!(chunk.constants, function.chunk.constants);
+}
+
+#[rstest]
+fn assembles_labels_and_functions() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let text = "
+        .line 3
+        OP_CLOSURE \"my fn\" 1 [] {   ; a comment
+            OP_GET_LOCAL 1
+        end:
+            OP_RETURN
+        }
+    top:
+        OP_TRUE
+        .line 4
+        OP_JUMP_IF_FALSE end
+        OP_POP
+        OP_LOOP top
+    end:
+        OP_CONSTANT 2.5
+        OP_RETURN
+    ";
+    let chunk = assemble(text, &mut heap, &mut strings).unwrap();
+    assert_eq!(chunk.code, vec![29, 0, 9, 25, 4, 0, 16, 27, 8, 0, 1, 1, 0]);
+    assert_eq!(chunk.line_nos, vec![(3, 2), (4, 5)]);
+    assert_eq!(
+        chunk.to_assembly().unwrap(),
+        "    .line 3
+    OP_CLOSURE \"my fn\" 1 [] {
+        OP_GET_LOCAL 1
+        OP_RETURN
+    }
+L0:
+    OP_TRUE
+    .line 4
+    OP_JUMP_IF_FALSE L1
+    OP_POP
+    OP_LOOP L0
+L1:
+    OP_CONSTANT 2.5
+    OP_RETURN
+"
+    );
+}
+
+#[rstest]
+fn to_assembly_rejects_function_constants() {
+    let mut heap = Heap::new();
+    let function = heap.alloc(Function::new(Some("f".to_string())));
+    let mut chunk = Chunk::new();
+    let value = Value::Obj(Object::Function(function));
+    chunk.push_op_code(Op::Constant { value }, 1, 1, 1);
+    assert_eq!(
+        chunk.to_assembly(),
+        Err("Can't write constant fn <f> as assembly.".to_string())
+    );
+}
+
+#[rstest]
+#[case::unknown_instruction("OP_FOO", vec![(1, 1, "Unknown instruction 'OP_FOO'.")])]
+#[case::missing_operand("  OP_CALL", vec![(1, 10, "Expect an argument count from 0 to 255.")])]
+#[case::operand_too_big("OP_GET_LOCAL 256", vec![(1, 14, "Expect a slot from 0 to 255.")])]
+#[case::extra_operand("OP_NIL 1", vec![(1, 8, "Expect end of line.")])]
+#[case::bad_constant("OP_CONSTANT x", vec![(1, 13, "Expect constant value.")])]
+#[case::bad_escape("OP_CONSTANT \"a\\q\"", vec![(1, 15, "Invalid escape sequence '\\q'.")])]
+#[case::unterminated("OP_CONSTANT \"abc", vec![(1, 13, "Unterminated string.")])]
+#[case::undefined_label("OP_JUMP nowhere\nOP_JUMP nowhere", vec![
+    (1, 9, "Undefined label 'nowhere'."),
+    (2, 9, "Undefined label 'nowhere'."),
+])]
+#[case::duplicate_label("a:\na: OP_NIL", vec![(2, 1, "Label 'a' is already defined.")])]
+#[case::wrong_direction("a: OP_JUMP a", vec![(1, 12, "Label 'a' is before the jump.")])]
+#[case::unknown_directive(".column 1", vec![(1, 1, "Unknown directive '.column'.")])]
+#[case::unmatched_brace("}", vec![(1, 1, "Unmatched '}'.")])]
+#[case::unclosed_function("OP_CLOSURE f 0 [] {\nOP_NIL", vec![(1, 1, "Expect '}' to close 'f'.")])]
+#[case::bad_capture(
+    "OP_CLOSURE f 0 [global 1] {\n}",
+    vec![(1, 17, "Expect 'local' or 'upvalue'."), (2, 1, "Unmatched '}'.")]
+)]
+fn assembly_errors(#[case] text: &str, #[case] expected: Vec<(u32, usize, &str)>) {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let expected: Vec<(u32, usize, String)> = expected
+        .into_iter()
+        .map(|(line, column, message)| (line, column, message.to_string()))
+        .collect();
+    assert_eq!(
+        assemble(text, &mut heap, &mut strings).err(),
+        Some(expected)
+    );
+}
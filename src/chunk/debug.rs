@@ -1,116 +1,258 @@
-use crate::chunk::OpCode;
-use std::convert::TryInto;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
 
-use super::Chunk;
+use rstest::rstest;
+
+use super::{Chunk, Op, OpCode};
+#[cfg(test)]
+use crate::memory::Heap;
+use crate::object::Object;
+#[cfg(test)]
+use crate::strings::Strings;
+use crate::value::Value;
 
 impl Chunk {
-    pub fn disassemble(&self, name: String) {
-        println!("== {name} ==");
+    /// A listing of this chunk's instructions headed by `name`, followed by
+    /// the listings of the functions it creates.
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = String::new();
+        self.disassemble_to(name, &mut out).unwrap();
+        out
+    }
+
+    /// Write the listing returned by `disassemble` to `out`.
+    pub fn disassemble_to<W: Write>(&self, name: &str, out: &mut W) -> fmt::Result {
+        writeln!(out, "== {name} ==")?;
         let mut code_idx = 0;
         let mut op_idx = 0;
         let mut prev_line_no = None;
         while code_idx < self.code.len() {
-            code_idx += self.decode(code_idx).1;
+            let line_no = self.get_line_no(op_idx);
+            code_idx = self.write_op_info(out, code_idx, line_no, prev_line_no)?;
             op_idx += 1;
-            self.print_op_info(code_idx, Some(op_idx), prev_line_no);
-            prev_line_no = Some(self.get_line_no(op_idx));
+            prev_line_no = Some(line_no);
+        }
+        for constant in &self.constants {
+            if let Value::Obj(Object::Function(function)) = constant {
+                let name = function.name.as_deref().unwrap_or("<script>");
+                function.chunk.disassemble_to(name, out)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn disassemble_code(&self, code_idx: usize) {
-        self.print_op_info(code_idx, None, None);
+    /// The instruction at `code_idx`, as one line of a listing.
+    pub fn disassemble_code(&self, code_idx: usize) -> String {
+        let mut out = String::new();
+        self.disassemble_code_to(code_idx, &mut out).unwrap();
+        out
     }
 
-    fn print_op_info(&self, code_idx: usize, op_idx: Option<usize>, prev_line_no: Option<u32>) {
-        let idx = op_idx.unwrap_or(self.get_op_idx(code_idx));
-        let line_no = self.get_line_no(idx);
-        let line_no_string = match prev_line_no {
-            Some(x) => {
-                if line_no == x {
-                    "   |".to_owned()
-                } else {
-                    format!("{line_no:04}")
-                }
-            }
-            None => {
-                format!("{line_no:4}")
-            }
-        };
-        print!("{idx:04} {line_no_string} ");
-        let code_val = self.code[code_idx];
-        let op_code: OpCode = match code_val.try_into() {
-            Ok(op_code) => op_code,
-            Err(()) => {
-                panic!("Invalid op code {} found at index {}!", code_val, idx);
-            }
-        };
-        match op_code {
-            OpCode::Return => println!("OP_RETURN"),
-            OpCode::Constant => {
-                println!(
-                    "OP_CONSTANT           {idx} '{}'",
-                    self.get_const_short(idx)
-                );
-            }
-            OpCode::ConstantLong => {
-                println!("OP_CONSTANT_LONG      {idx} '{}'", self.get_const_long(idx));
-            }
-            OpCode::Negate => println!("OP_NEGATE"),
-            OpCode::Add => println!("OP_ADD"),
-            OpCode::Subtract => println!("OP_SUBTRACT"),
-            OpCode::Multiply => println!("OP_MULTIPLY"),
-            OpCode::Divide => println!("OP_DIVIDE"),
-            OpCode::False => println!("OP_FALSE"),
-            OpCode::True => println!("OP_TRUE"),
-            OpCode::Nil => println!("OP_NIL"),
-            OpCode::Not => println!("OP_NOT"),
-            OpCode::Equal => println!("OP_EQUAL"),
-            OpCode::Greater => println!("OP_GREATER"),
-            OpCode::Less => println!("OP_LESS"),
-            OpCode::Print => println!("OP_PRINT"),
-            OpCode::Pop => println!("OP_POP"),
-            OpCode::DefineGlobal => {
-                println!(
-                    "OP_DEFINE_GLOBAL      {idx} '{}'",
-                    self.get_const_short(idx)
-                );
-            }
-            OpCode::DefineGlobalLong => {
-                println!("OP_DEFINE_GLOBAL_LONG {idx} '{}'", self.get_const_long(idx));
-            }
-            OpCode::GetGlobal => {
-                println!(
-                    "OP_GET_GLOBAL         {idx} '{}'",
-                    self.get_const_short(idx)
-                );
-            }
-            OpCode::SetGlobalLong => {
-                println!("OP_SET_GLOBAL_LONG    {idx} '{}'", self.get_const_long(idx));
-            }
-            OpCode::SetGlobal => {
-                println!(
-                    "OP_SET_GLOBAL         {idx} '{}'",
-                    self.get_const_short(idx)
-                );
-            }
-            OpCode::GetGlobalLong => {
-                println!("OP_GET_GLOBAL_LONG    {idx} '{}'", self.get_const_long(idx));
-            }
-            OpCode::GetLocal => {
-                println!("OP_GET_LOCAL          {idx} '{}'", self.code[idx + 1]);
-            }
-            OpCode::SetLocal => {
-                println!("OP_SET_LOCAL          {idx} '{}'", self.code[idx + 1]);
+    /// Write the line returned by `disassemble_code` to `out`.
+    pub fn disassemble_code_to<W: Write>(&self, code_idx: usize, out: &mut W) -> fmt::Result {
+        let line_no = self.get_line_no(self.get_op_idx(code_idx));
+        self.write_op_info(out, code_idx, line_no, None)?;
+        Ok(())
+    }
+
+    /// Write the instruction at `code_idx`, returning the index of the next
+    /// one. The line number is left out if it is the same as the previous
+    /// instruction's.
+    fn write_op_info<W: Write>(
+        &self,
+        out: &mut W,
+        code_idx: usize,
+        line_no: u32,
+        prev_line_no: Option<u32>,
+    ) -> Result<usize, fmt::Error> {
+        write!(out, "{code_idx:04} ")?;
+        if prev_line_no == Some(line_no) {
+            write!(out, "   | ")?;
+        } else {
+            write!(out, "{line_no:4} ")?;
+        }
+        let (op, size) = self.decode(code_idx);
+        let name = self.op_name(&op, code_idx);
+        let next = code_idx + size;
+        match op {
+            Op::Constant { .. }
+            | Op::DefineGlobal { .. }
+            | Op::GetGlobal { .. }
+            | Op::SetGlobal { .. }
+            | Op::Class { .. }
+            | Op::GetProperty { .. }
+            | Op::SetProperty { .. }
+            | Op::Method { .. }
+            | Op::GetSuper { .. } => {
+                let (const_idx, value) = self.constant_operand(code_idx);
+                writeln!(out, "{name:<21} {const_idx:4} '{value}'")?;
             }
-            OpCode::JumpIfFalse => {
-                println!("OP_JUMP_IF_FALSE      {idx} '{}'", self.get_u16(idx + 1));
+            Op::GetLocal { idx }
+            | Op::SetLocal { idx }
+            | Op::GetUpvalue { idx }
+            | Op::SetUpvalue { idx } => writeln!(out, "{name:<21} {idx:4}")?,
+            Op::Call { arg_count } => writeln!(out, "{name:<21} {arg_count:4}")?,
+            Op::BuildList { item_count } => writeln!(out, "{name:<21} {item_count:4}")?,
+            Op::BuildMap { entry_count } => writeln!(out, "{name:<21} {entry_count:4}")?,
+            Op::JumpIfFalse { offset } | Op::Jump { offset } | Op::ForIter { offset } => {
+                writeln!(out, "{name:<21} {code_idx:4} -> {}", next + offset as usize)?;
             }
-            OpCode::Jump => {
-                println!("OP_JUMP               {idx} '{}'", self.get_u16(idx + 1));
+            Op::Loop { offset } => {
+                writeln!(out, "{name:<21} {code_idx:4} -> {}", next - offset as usize)?;
             }
-            OpCode::Loop => {
-                println!("OP_LOOP               {idx} '{}'", self.get_u16(idx + 1));
+            Op::Closure { upvalues, .. } => {
+                let (const_idx, value) = self.constant_operand(code_idx);
+                writeln!(out, "{name:<21} {const_idx:4} {value}")?;
+                let mut upvalue_idx = next - 2 * upvalues.len();
+                for upvalue in upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    writeln!(
+                        out,
+                        "{upvalue_idx:04}    |                       {kind} {}",
+                        upvalue.index
+                    )?;
+                    upvalue_idx += 2;
+                }
             }
+            _ => writeln!(out, "{name}")?,
+        }
+        Ok(next)
+    }
+
+    /// The name of the instruction at `code_idx`, including its `_LONG`
+    /// suffix if it has one.
+    pub(super) fn op_name(&self, op: &Op, code_idx: usize) -> String {
+        let name = mnemonic(op);
+        if self.is_long(code_idx) {
+            format!("{name}_LONG")
+        } else {
+            name.to_string()
+        }
+    }
+
+    pub(super) fn constant_operand(&self, code_idx: usize) -> (usize, Value) {
+        if self.is_long(code_idx) {
+            let const_idx = self.get_u16(code_idx + 1) as usize;
+            (const_idx, self.constants[const_idx])
+        } else {
+            let const_idx = self.code[code_idx + 1] as usize;
+            (const_idx, self.constants[const_idx])
         }
     }
+
+    fn is_long(&self, code_idx: usize) -> bool {
+        matches!(
+            OpCode::try_from(self.code[code_idx]),
+            Ok(OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::GetSuperLong)
+        )
+    }
+}
+
+/// The name of `op`, as used by the disassembler and assembler.
+pub(super) fn mnemonic(op: &Op) -> &'static str {
+    match op {
+        Op::Return => "OP_RETURN",
+        Op::Constant { .. } => "OP_CONSTANT",
+        Op::Nil => "OP_NIL",
+        Op::False => "OP_FALSE",
+        Op::True => "OP_TRUE",
+        Op::Negate => "OP_NEGATE",
+        Op::Add => "OP_ADD",
+        Op::Subtract => "OP_SUBTRACT",
+        Op::Multiply => "OP_MULTIPLY",
+        Op::Divide => "OP_DIVIDE",
+        Op::Not => "OP_NOT",
+        Op::Equal => "OP_EQUAL",
+        Op::Greater => "OP_GREATER",
+        Op::Less => "OP_LESS",
+        Op::Print => "OP_PRINT",
+        Op::Pop => "OP_POP",
+        Op::DefineGlobal { .. } => "OP_DEFINE_GLOBAL",
+        Op::GetGlobal { .. } => "OP_GET_GLOBAL",
+        Op::SetGlobal { .. } => "OP_SET_GLOBAL",
+        Op::GetLocal { .. } => "OP_GET_LOCAL",
+        Op::SetLocal { .. } => "OP_SET_LOCAL",
+        Op::JumpIfFalse { .. } => "OP_JUMP_IF_FALSE",
+        Op::Jump { .. } => "OP_JUMP",
+        Op::Loop { .. } => "OP_LOOP",
+        Op::Call { .. } => "OP_CALL",
+        Op::Closure { .. } => "OP_CLOSURE",
+        Op::GetUpvalue { .. } => "OP_GET_UPVALUE",
+        Op::SetUpvalue { .. } => "OP_SET_UPVALUE",
+        Op::CloseUpvalue => "OP_CLOSE_UPVALUE",
+        Op::Class { .. } => "OP_CLASS",
+        Op::GetProperty { .. } => "OP_GET_PROPERTY",
+        Op::SetProperty { .. } => "OP_SET_PROPERTY",
+        Op::Method { .. } => "OP_METHOD",
+        Op::Inherit => "OP_INHERIT",
+        Op::GetSuper { .. } => "OP_GET_SUPER",
+        Op::BuildList { .. } => "OP_BUILD_LIST",
+        Op::BuildMap { .. } => "OP_BUILD_MAP",
+        Op::GetIndex => "OP_GET_INDEX",
+        Op::SetIndex => "OP_SET_INDEX",
+        Op::GetIter => "OP_GET_ITER",
+        Op::ForIter { .. } => "OP_FOR_ITER",
+    }
+}
+
+#[rstest]
+fn disassembly_lists_every_function() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let text = "
+    .line 1
+        OP_CLOSURE f 0 [] {
+        .line 2
+            OP_CLOSURE g 0 [local 0] {
+                OP_GET_UPVALUE 0
+                OP_RETURN
+            }
+            OP_RETURN
+        }
+        OP_DEFINE_GLOBAL f
+    .line 3
+    top:
+        OP_TRUE
+        OP_JUMP_IF_FALSE end
+        OP_POP
+        OP_LOOP top
+    end:
+        OP_CONSTANT \"done\"
+        OP_RETURN
+    ";
+    let chunk = Chunk::assemble(text, &mut heap, &mut strings).unwrap();
+    assert_eq!(
+        chunk.disassemble("<script>"),
+        "\
+== <script> ==
+0000    1 OP_CLOSURE               0 fn <f>
+0002    | OP_DEFINE_GLOBAL         1 '\"f\"'
+0004    3 OP_TRUE
+0005    | OP_JUMP_IF_FALSE         5 -> 12
+0008    | OP_POP
+0009    | OP_LOOP                  9 -> 4
+0012    | OP_CONSTANT              2 '\"done\"'
+0014    | OP_RETURN
+== f ==
+0000    2 OP_CLOSURE               0 fn <g>
+0002    |                       local 0
+0004    | OP_RETURN
+== g ==
+0000    2 OP_GET_UPVALUE           0
+0002    | OP_RETURN
+"
+    );
+    assert_eq!(
+        chunk.disassemble_code(9),
+        "0009    3 OP_LOOP                  9 -> 4\n"
+    );
 }
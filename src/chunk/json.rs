@@ -0,0 +1,232 @@
+//! A JSON dump of a script and the functions it creates, for tools that
+//! want the compiler's output without parsing the disassembly.
+//!
+//! Each function is an object with its `name` (`null` for the script),
+//! `arity`, `upvalue_count`, `instructions` and `constants`. Instructions
+//! have their byte `offset`, `size`, `op` name, `line` and `column`, plus
+//! any operands: `constant` (an index into `constants`), `slot`, `upvalue`,
+//! `arg_count`, `item_count`, `entry_count`, `jump_offset` and `target`, or
+//! `upvalues` for a closure.
+//! Constants have a `type` of `nil`, `bool`, `number`, `string` or
+//! `function`, and a `value` unless they are nil.
+
+use std::fmt::{self, Write};
+
+use rstest::rstest;
+
+use super::{Chunk, Op};
+#[cfg(test)]
+use crate::memory::Heap;
+use crate::object::Object;
+#[cfg(test)]
+use crate::strings::Strings;
+use crate::value::Value;
+
+impl Chunk {
+    /// This chunk, taken as a script, and every function it creates, as a
+    /// JSON object.
+    pub fn dump_json(&self) -> String {
+        let mut out = String::new();
+        self.dump_json_to(&mut out).unwrap();
+        out
+    }
+
+    /// Write the JSON returned by `dump_json` to `out`.
+    pub fn dump_json_to<W: Write>(&self, out: &mut W) -> fmt::Result {
+        self.write_function(out, None, 0, 0)
+    }
+
+    fn write_function<W: Write>(
+        &self,
+        out: &mut W,
+        name: Option<&str>,
+        arity: usize,
+        upvalue_count: usize,
+    ) -> fmt::Result {
+        write!(out, "{{\"name\":")?;
+        match name {
+            Some(name) => write_string(out, name)?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"arity\":{arity},\"upvalue_count\":{upvalue_count},\"instructions\":["
+        )?;
+        let mut code_idx = 0;
+        let mut op_idx = 0;
+        while code_idx < self.code.len() {
+            if op_idx > 0 {
+                write!(out, ",")?;
+            }
+            code_idx = self.write_instruction(out, code_idx, op_idx)?;
+            op_idx += 1;
+        }
+        write!(out, "],\"constants\":[")?;
+        for (i, constant) in self.constants.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write_constant(out, constant)?;
+        }
+        write!(out, "]}}")
+    }
+
+    /// Write the instruction at `code_idx`, returning the index of the next
+    /// one.
+    fn write_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        code_idx: usize,
+        op_idx: usize,
+    ) -> Result<usize, fmt::Error> {
+        let (op, size) = self.decode(code_idx);
+        let next = code_idx + size;
+        write!(out, "{{\"offset\":{code_idx},\"size\":{size},\"op\":")?;
+        write_string(out, &self.op_name(&op, code_idx))?;
+        let (column, _) = self.get_column(op_idx);
+        write!(
+            out,
+            ",\"line\":{},\"column\":{column}",
+            self.get_line_no(op_idx)
+        )?;
+        match op {
+            Op::Constant { .. }
+            | Op::DefineGlobal { .. }
+            | Op::GetGlobal { .. }
+            | Op::SetGlobal { .. }
+            | Op::Class { .. }
+            | Op::GetProperty { .. }
+            | Op::SetProperty { .. }
+            | Op::Method { .. }
+            | Op::GetSuper { .. } => {
+                write!(out, ",\"constant\":{}", self.constant_operand(code_idx).0)?;
+            }
+            Op::GetLocal { idx } | Op::SetLocal { idx } => write!(out, ",\"slot\":{idx}")?,
+            Op::GetUpvalue { idx } | Op::SetUpvalue { idx } => {
+                write!(out, ",\"upvalue\":{idx}")?;
+            }
+            Op::Call { arg_count } => write!(out, ",\"arg_count\":{arg_count}")?,
+            Op::BuildList { item_count } => write!(out, ",\"item_count\":{item_count}")?,
+            Op::BuildMap { entry_count } => write!(out, ",\"entry_count\":{entry_count}")?,
+            Op::JumpIfFalse { offset } | Op::Jump { offset } | Op::ForIter { offset } => write!(
+                out,
+                ",\"jump_offset\":{offset},\"target\":{}",
+                next + offset as usize
+            )?,
+            Op::Loop { offset } => write!(
+                out,
+                ",\"jump_offset\":{offset},\"target\":{}",
+                next - offset as usize
+            )?,
+            Op::Closure { upvalues, .. } => {
+                let constant = self.constant_operand(code_idx).0;
+                write!(out, ",\"constant\":{constant},\"upvalues\":[")?;
+                for (i, upvalue) in upvalues.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    write!(
+                        out,
+                        "{{\"is_local\":{},\"index\":{}}}",
+                        upvalue.is_local, upvalue.index
+                    )?;
+                }
+                write!(out, "]")?;
+            }
+            _ => {}
+        }
+        write!(out, "}}")?;
+        Ok(next)
+    }
+}
+
+fn write_constant<W: Write>(out: &mut W, constant: &Value) -> fmt::Result {
+    match constant {
+        Value::Nil => write!(out, "{{\"type\":\"nil\"}}"),
+        Value::Bool(b) => write!(out, "{{\"type\":\"bool\",\"value\":{b}}}"),
+        // JSON has no infinities or NaN.
+        Value::Number(n) if !n.is_finite() => {
+            write!(out, "{{\"type\":\"number\",\"value\":null}}")
+        }
+        Value::Number(n) => write!(out, "{{\"type\":\"number\",\"value\":{n}}}"),
+        Value::Obj(Object::String { chars }) => {
+            write!(out, "{{\"type\":\"string\",\"value\":")?;
+            write_string(out, chars)?;
+            write!(out, "}}")
+        }
+        Value::Obj(Object::Function(function)) => {
+            write!(out, "{{\"type\":\"function\",\"value\":")?;
+            function.chunk.write_function(
+                out,
+                function.name.as_deref(),
+                function.arity,
+                function.upvalue_count,
+            )?;
+            write!(out, "}}")
+        }
+        Value::Obj(object) => {
+            write!(out, "{{\"type\":\"object\",\"value\":")?;
+            write_string(out, &object.to_string())?;
+            write!(out, "}}")
+        }
+    }
+}
+
+fn write_string<W: Write>(out: &mut W, string: &str) -> fmt::Result {
+    write!(out, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{c}")?,
+        }
+    }
+    write!(out, "\"")
+}
+
+#[rstest]
+fn dump_json_describes_every_function() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let text = "
+    .line 1
+        OP_CLOSURE f 1 [local 0] {
+            OP_GET_UPVALUE 0
+            OP_RETURN
+        }
+    .line 2
+        OP_JUMP_IF_FALSE next
+    next:
+        OP_CONSTANT \"a\\\"b\"
+        OP_CONSTANT 1.5
+        OP_CONSTANT nil
+        OP_CONSTANT true
+        OP_RETURN
+    ";
+    let chunk = Chunk::assemble(text, &mut heap, &mut strings).unwrap();
+    assert_eq!(
+        chunk.dump_json(),
+        concat!(
+            r#"{"name":null,"arity":0,"upvalue_count":0,"instructions":["#,
+            r#"{"offset":0,"size":4,"op":"OP_CLOSURE","line":1,"column":0,"constant":0,"#,
+            r#""upvalues":[{"is_local":true,"index":0}]},"#,
+            r#"{"offset":4,"size":3,"op":"OP_JUMP_IF_FALSE","line":2,"column":0,"#,
+            r#""jump_offset":0,"target":7},"#,
+            r#"{"offset":7,"size":2,"op":"OP_CONSTANT","line":2,"column":0,"constant":1},"#,
+            r#"{"offset":9,"size":2,"op":"OP_CONSTANT","line":2,"column":0,"constant":2},"#,
+            r#"{"offset":11,"size":2,"op":"OP_CONSTANT","line":2,"column":0,"constant":3},"#,
+            r#"{"offset":13,"size":2,"op":"OP_CONSTANT","line":2,"column":0,"constant":4},"#,
+            r#"{"offset":15,"size":1,"op":"OP_RETURN","line":2,"column":0}],"#,
+            r#""constants":[{"type":"function","value":"#,
+            r#"{"name":"f","arity":1,"upvalue_count":1,"instructions":["#,
+            r#"{"offset":0,"size":2,"op":"OP_GET_UPVALUE","line":1,"column":0,"upvalue":0},"#,
+            r#"{"offset":2,"size":1,"op":"OP_RETURN","line":1,"column":0}],"constants":[]}},"#,
+            r#"{"type":"string","value":"a\"b"},{"type":"number","value":1.5},"#,
+            r#"{"type":"nil"},{"type":"bool","value":true}]}"#,
+        )
+    );
+}
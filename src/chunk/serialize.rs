@@ -0,0 +1,283 @@
+//! The `.loxc` bytecode format.
+//!
+//! A file is the `MAGIC` bytes and a little-endian `u16` format version,
+//! followed by the script's chunk. A chunk is its code, constant pool, line
+//! table and column table, each prefixed by a `u32` length. Constants are a
+//! tag byte followed by the value; functions carry their own nested chunk.
+//! Sources are not stored, so errors in loaded code are reported without a
+//! snippet.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::{Chunk, VerifyError, MAX_FUNCTION_DEPTH};
+use crate::memory::Heap;
+use crate::object::{Function, Object};
+use crate::strings::Strings;
+use crate::value::Value;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the encoding or the instruction set changes.
+pub const FORMAT_VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Malformed(String),
+    Invalid(Vec<VerifyError>),
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a Lox bytecode file."),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode version {version} (expected {FORMAT_VERSION})."
+            ),
+            LoadError::Malformed(reason) => write!(f, "Malformed bytecode: {reason}."),
+            LoadError::Invalid(errors) => {
+                write!(f, "Invalid bytecode:")?;
+                for error in errors {
+                    write!(f, "\n    {error}")?;
+                }
+                Ok(())
+            }
+            LoadError::Io(kind) => write!(f, "Could not read bytecode: {kind}."),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                LoadError::Malformed("unexpected end of file".to_string())
+            }
+            kind => LoadError::Io(kind),
+        }
+    }
+}
+
+impl Chunk {
+    /// Write this chunk, with the file header, in the `.loxc` format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        self.write_body(writer)
+    }
+
+    /// Read a chunk written by `write_to`, checking it with `verify`. Its
+    /// strings and functions are allocated on `heap` but not rooted, so the
+    /// caller must make the chunk reachable before the next collection.
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        heap: &mut Heap,
+        strings: &mut Strings,
+    ) -> Result<Chunk, LoadError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let version = read_u16(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let chunk = Chunk::read_body(reader, heap, strings, 0)?;
+        if reader.read(&mut [0])? != 0 {
+            return Err(LoadError::Malformed("trailing data".to_string()));
+        }
+        chunk.verify().map_err(LoadError::Invalid)?;
+        Ok(chunk)
+    }
+
+    fn write_body<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_len(writer, self.code.len())?;
+        writer.write_all(&self.code)?;
+
+        write_len(writer, self.constants.len())?;
+        for constant in &self.constants {
+            write_constant(writer, constant)?;
+        }
+
+        write_len(writer, self.line_nos.len())?;
+        for (line_no, count) in &self.line_nos {
+            writer.write_all(&line_no.to_le_bytes())?;
+            writer.write_all(&count.to_le_bytes())?;
+        }
+
+        write_len(writer, self.columns.len())?;
+        for &(column, width) in &self.columns {
+            write_len(writer, column)?;
+            write_len(writer, width)?;
+        }
+        Ok(())
+    }
+
+    /// Read a chunk's body, `depth` functions deep in the script.
+    fn read_body<R: Read>(
+        reader: &mut R,
+        heap: &mut Heap,
+        strings: &mut Strings,
+        depth: usize,
+    ) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new();
+
+        let code_len = read_len(reader)?;
+        chunk.code = read_bytes(reader, code_len)?;
+
+        for _ in 0..read_len(reader)? {
+            let constant = read_constant(reader, heap, strings, depth)?;
+            chunk.constants.push(constant);
+        }
+
+        for _ in 0..read_len(reader)? {
+            let line_no = read_u32(reader)?;
+            let count = read_u32(reader)?;
+            chunk.line_nos.push((line_no, count));
+        }
+
+        for _ in 0..read_len(reader)? {
+            let column = read_len(reader)?;
+            let width = read_len(reader)?;
+            chunk.columns.push((column, width));
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_constant<W: Write>(writer: &mut W, constant: &Value) -> io::Result<()> {
+    match constant {
+        Value::Nil => writer.write_all(&[TAG_NIL]),
+        Value::Bool(false) => writer.write_all(&[TAG_FALSE]),
+        Value::Bool(true) => writer.write_all(&[TAG_TRUE]),
+        Value::Number(n) => {
+            writer.write_all(&[TAG_NUMBER])?;
+            writer.write_all(&n.to_le_bytes())
+        }
+        Value::Obj(Object::String { chars }) => {
+            writer.write_all(&[TAG_STRING])?;
+            write_string(writer, chars)
+        }
+        Value::Obj(Object::Function(function)) => {
+            writer.write_all(&[TAG_FUNCTION])?;
+            write_len(writer, function.arity)?;
+            write_len(writer, function.upvalue_count)?;
+            match &function.name {
+                Some(name) => {
+                    writer.write_all(&[1])?;
+                    write_string(writer, name)?;
+                }
+                None => writer.write_all(&[0])?,
+            }
+            function.chunk.write_body(writer)
+        }
+        Value::Obj(object) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't serialize constant {object}."),
+        )),
+    }
+}
+
+fn read_constant<R: Read>(
+    reader: &mut R,
+    heap: &mut Heap,
+    strings: &mut Strings,
+    depth: usize,
+) -> Result<Value, LoadError> {
+    match read_u8(reader)? {
+        TAG_NIL => Ok(Value::Nil),
+        TAG_FALSE => Ok(Value::Bool(false)),
+        TAG_TRUE => Ok(Value::Bool(true)),
+        TAG_NUMBER => {
+            let mut bytes = [0; 8];
+            reader.read_exact(&mut bytes)?;
+            Ok(Value::Number(f64::from_le_bytes(bytes)))
+        }
+        TAG_STRING => {
+            let chars = strings.new_string(heap, read_string(reader)?);
+            Ok(Value::Obj(Object::String { chars }))
+        }
+        TAG_FUNCTION => {
+            let arity = read_len(reader)?;
+            let upvalue_count = read_len(reader)?;
+            let name = match read_u8(reader)? {
+                0 => None,
+                1 => Some(read_string(reader)?),
+                flag => return Err(LoadError::Malformed(format!("bad name flag {flag}"))),
+            };
+            if depth == MAX_FUNCTION_DEPTH {
+                return Err(LoadError::Malformed("functions nested too deeply".to_string()));
+            }
+            let chunk = Chunk::read_body(reader, heap, strings, depth + 1)?;
+            let function = heap.alloc(Function {
+                arity,
+                upvalue_count,
+                chunk,
+                name,
+            });
+            Ok(Value::Obj(Object::Function(function)))
+        }
+        tag => Err(LoadError::Malformed(format!("unknown constant tag {tag}"))),
+    }
+}
+
+fn write_len<W: Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Length too large."))?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    write_len(writer, string.len())?;
+    writer.write_all(string.as_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    Ok(read_u32(reader)? as usize)
+}
+
+/// Read `len` bytes without trusting `len` for the initial allocation.
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, LoadError> {
+    let len = read_len(reader)?;
+    String::from_utf8(read_bytes(reader, len)?)
+        .map_err(|_| LoadError::Malformed("invalid UTF-8 in string".to_string()))
+}
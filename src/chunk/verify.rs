@@ -0,0 +1,652 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+
+use rstest::rstest;
+
+use super::{Chunk, Op, OpCode, MAX_FUNCTION_DEPTH};
+use crate::object::Object;
+use crate::value::Value;
+
+/// A problem found by `Chunk::verify`, at byte `offset` of the chunk
+/// belonging to `function` (`None` for the top-level script).
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function: Option<String>,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    InvalidOpCode(u8),
+    /// The instruction's operands run past the end of the code.
+    Truncated,
+    ConstantOutOfRange(usize),
+    /// The constant used as a name or closure has the wrong type.
+    WrongConstantType {
+        index: usize,
+        expected: &'static str,
+    },
+    InvalidUpvalueFlag(u8),
+    JumpOutOfBounds,
+    /// The jump lands in the middle of an instruction.
+    JumpIntoInstruction(usize),
+    LocalOutOfRange(u8),
+    UpvalueOutOfRange(u8),
+    StackUnderflow,
+    /// Two paths reach the instruction with different stack depths.
+    InconsistentStackDepth {
+        expected: usize,
+        found: usize,
+    },
+    MissingReturn,
+    /// The line or column table doesn't have one entry per instruction.
+    LineTableMismatch,
+    /// The closure's function is nested more deeply than the verifier
+    /// follows.
+    NestedTooDeeply,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "{name}() at {}: ", self.offset)?,
+            None => write!(f, "script at {}: ", self.offset)?,
+        }
+        match &self.kind {
+            VerifyErrorKind::InvalidOpCode(code) => write!(f, "invalid op code {code}"),
+            VerifyErrorKind::Truncated => write!(f, "instruction truncated"),
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "constant {index} out of range")
+            }
+            VerifyErrorKind::WrongConstantType { index, expected } => {
+                write!(f, "constant {index} is not a {expected}")
+            }
+            VerifyErrorKind::InvalidUpvalueFlag(flag) => write!(f, "invalid upvalue flag {flag}"),
+            VerifyErrorKind::JumpOutOfBounds => write!(f, "jump target out of bounds"),
+            VerifyErrorKind::JumpIntoInstruction(target) => {
+                write!(f, "jump target {target} is inside an instruction")
+            }
+            VerifyErrorKind::LocalOutOfRange(idx) => write!(f, "local {idx} out of range"),
+            VerifyErrorKind::UpvalueOutOfRange(idx) => write!(f, "upvalue {idx} out of range"),
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VerifyErrorKind::InconsistentStackDepth { expected, found } => {
+                write!(f, "stack depth {found} where {expected} was expected")
+            }
+            VerifyErrorKind::MissingReturn => write!(f, "chunk does not end in a return"),
+            VerifyErrorKind::LineTableMismatch => {
+                write!(f, "line table does not match instructions")
+            }
+            VerifyErrorKind::NestedTooDeeply => write!(f, "functions nested too deeply"),
+        }
+    }
+}
+
+impl Chunk {
+    /// Check that this chunk, taken as a script, and the chunks of every
+    /// function it creates can be run without the VM panicking or reading
+    /// outside the stack. Types of runtime values are not checked.
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        let mut errors = vec![];
+        Verifier {
+            chunk: self,
+            function: None,
+            arity: 0,
+            upvalue_count: 0,
+            depth: 0,
+            errors: &mut errors,
+        }
+        .verify();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// What an instruction's operand bytes hold.
+enum Operands {
+    None,
+    Byte,
+    Jump,
+    Constant { long: bool },
+    Name { long: bool },
+    Closure { long: bool },
+}
+
+impl Operands {
+    /// Size of the instruction, not counting a closure's upvalue pairs.
+    fn size(&self) -> usize {
+        match self {
+            Operands::None => 1,
+            Operands::Byte => 2,
+            Operands::Jump => 3,
+            Operands::Constant { long } | Operands::Name { long } | Operands::Closure { long } => {
+                if *long {
+                    3
+                } else {
+                    2
+                }
+            }
+        }
+    }
+}
+
+impl OpCode {
+    fn operands(&self) -> Operands {
+        match self {
+            OpCode::Constant => Operands::Constant { long: false },
+            OpCode::ConstantLong => Operands::Constant { long: true },
+            OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetSuper => Operands::Name { long: false },
+            OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::ClassLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::MethodLong
+            | OpCode::GetSuperLong => Operands::Name { long: true },
+            OpCode::Closure => Operands::Closure { long: false },
+            OpCode::ClosureLong => Operands::Closure { long: true },
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::BuildList
+            | OpCode::BuildMap => Operands::Byte,
+            OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop | OpCode::ForIter => {
+                Operands::Jump
+            }
+            _ => Operands::None,
+        }
+    }
+}
+
+/// How many values `op` needs on the stack, and how many it leaves in their
+/// place.
+fn stack_effect(op: &Op) -> (usize, usize) {
+    match op {
+        Op::Return | Op::Print | Op::Pop | Op::DefineGlobal { .. } | Op::CloseUpvalue => (1, 0),
+        Op::Constant { .. }
+        | Op::Nil
+        | Op::True
+        | Op::False
+        | Op::GetGlobal { .. }
+        | Op::GetLocal { .. }
+        | Op::Closure { .. }
+        | Op::GetUpvalue { .. }
+        | Op::Class { .. } => (0, 1),
+        Op::Negate
+        | Op::Not
+        | Op::SetGlobal { .. }
+        | Op::SetLocal { .. }
+        | Op::JumpIfFalse { .. }
+        | Op::SetUpvalue { .. }
+        | Op::GetProperty { .. }
+        | Op::GetIter => (1, 1),
+        Op::Add
+        | Op::Subtract
+        | Op::Multiply
+        | Op::Divide
+        | Op::Equal
+        | Op::Greater
+        | Op::Less
+        | Op::SetProperty { .. }
+        | Op::Method { .. }
+        | Op::Inherit
+        | Op::GetSuper { .. }
+        | Op::GetIndex => (2, 1),
+        Op::SetIndex => (3, 1),
+        // The item is only pushed when the loop goes on.
+        Op::ForIter { .. } => (1, 2),
+        Op::Jump { .. } | Op::Loop { .. } => (0, 0),
+        Op::Call { arg_count } => (*arg_count as usize + 1, 1),
+        Op::BuildList { item_count } => (*item_count as usize, 1),
+        Op::BuildMap { entry_count } => (2 * *entry_count as usize, 1),
+    }
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    function: Option<String>,
+    arity: usize,
+    upvalue_count: usize,
+    /// How many functions deep `chunk` is in the script.
+    depth: usize,
+    errors: &'a mut Vec<VerifyError>,
+}
+
+impl Verifier<'_> {
+    fn verify(&mut self) {
+        let Some(starts) = self.check_instructions() else {
+            return;
+        };
+        let mut ok = self.check_jumps(&starts);
+
+        if self
+            .chunk
+            .line_nos
+            .iter()
+            .map(|(_, count)| *count as usize)
+            .sum::<usize>()
+            != starts.len()
+            || self.chunk.columns.len() != starts.len()
+        {
+            self.error(0, VerifyErrorKind::LineTableMismatch);
+            ok = false;
+        }
+        match starts.last() {
+            Some(&last) if self.chunk.code[last] == 0 => {}
+            last => {
+                self.error(last.copied().unwrap_or(0), VerifyErrorKind::MissingReturn);
+                ok = false;
+            }
+        }
+
+        if ok {
+            self.check_stack(&starts);
+        }
+    }
+
+    /// Check every instruction decodes, returning the offset each starts at.
+    /// Nested functions are verified as their closures are found.
+    fn check_instructions(&mut self) -> Option<Vec<usize>> {
+        let chunk = self.chunk;
+        let code = &chunk.code;
+        let mut starts = vec![];
+        let mut ok = true;
+        let mut offset = 0;
+        while offset < code.len() {
+            starts.push(offset);
+            let op_code: OpCode = match code[offset].try_into() {
+                Ok(op_code) => op_code,
+                Err(()) => {
+                    self.error(offset, VerifyErrorKind::InvalidOpCode(code[offset]));
+                    return None;
+                }
+            };
+            let operands = op_code.operands();
+            let size = operands.size();
+            if offset + size > code.len() {
+                self.error(offset, VerifyErrorKind::Truncated);
+                return None;
+            }
+            match operands {
+                Operands::Constant { long } => {
+                    ok &= self.check_constant(offset, long, None).is_some();
+                }
+                Operands::Name { long } => {
+                    ok &= self.check_constant(offset, long, Some("string")).is_some();
+                }
+                Operands::Closure { long } => {
+                    let function = match self.check_constant(offset, long, Some("function")) {
+                        Some(Value::Obj(Object::Function(function))) => function,
+                        // Without the function its upvalues can't be skipped.
+                        _ => return None,
+                    };
+                    let upvalues = offset + size..offset + size + 2 * function.upvalue_count;
+                    if upvalues.end > code.len() {
+                        self.error(offset, VerifyErrorKind::Truncated);
+                        return None;
+                    }
+                    for flag in upvalues.clone().step_by(2).map(|idx| code[idx]) {
+                        if flag > 1 {
+                            self.error(offset, VerifyErrorKind::InvalidUpvalueFlag(flag));
+                            ok = false;
+                        }
+                    }
+                    if self.depth == MAX_FUNCTION_DEPTH {
+                        self.error(offset, VerifyErrorKind::NestedTooDeeply);
+                        ok = false;
+                    } else {
+                        Verifier {
+                            chunk: &function.chunk,
+                            function: function.name.clone(),
+                            arity: function.arity,
+                            upvalue_count: function.upvalue_count,
+                            depth: self.depth + 1,
+                            errors: &mut *self.errors,
+                        }
+                        .verify();
+                    }
+                    offset = upvalues.end;
+                    continue;
+                }
+                _ => {}
+            }
+            offset += size;
+        }
+        if ok {
+            Some(starts)
+        } else {
+            None
+        }
+    }
+
+    /// Check the constant used by the instruction at `offset`, whose operand
+    /// is known to be in bounds, exists and, if `expected` is given, is of
+    /// that type.
+    fn check_constant(
+        &mut self,
+        offset: usize,
+        long: bool,
+        expected: Option<&'static str>,
+    ) -> Option<Value> {
+        let code = &self.chunk.code;
+        let index = if long {
+            (code[offset + 2] as usize) << 8 | code[offset + 1] as usize
+        } else {
+            code[offset + 1] as usize
+        };
+        let value = match self.chunk.constants.get(index) {
+            Some(value) => *value,
+            None => {
+                self.error(offset, VerifyErrorKind::ConstantOutOfRange(index));
+                return None;
+            }
+        };
+        let matches = matches!(
+            (expected, value),
+            (None, _)
+                | (Some("string"), Value::Obj(Object::String { .. }))
+                | (Some("function"), Value::Obj(Object::Function(_)))
+        );
+        if matches {
+            Some(value)
+        } else {
+            let expected = expected.unwrap();
+            self.error(
+                offset,
+                VerifyErrorKind::WrongConstantType { index, expected },
+            );
+            None
+        }
+    }
+
+    fn check_jumps(&mut self, starts: &[usize]) -> bool {
+        let boundaries: HashSet<usize> = starts.iter().copied().collect();
+        let mut ok = true;
+        for &offset in starts {
+            let (op, size) = self.chunk.decode(offset);
+            let target = match op {
+                Op::Jump { offset: jump }
+                | Op::JumpIfFalse { offset: jump }
+                | Op::ForIter { offset: jump } => Some(offset + size + jump as usize),
+                Op::Loop { offset: jump } => (offset + size).checked_sub(jump as usize),
+                _ => continue,
+            };
+            match target {
+                Some(target) if boundaries.contains(&target) => {}
+                Some(target) if target < self.chunk.code.len() => {
+                    self.error(offset, VerifyErrorKind::JumpIntoInstruction(target));
+                    ok = false;
+                }
+                _ => {
+                    self.error(offset, VerifyErrorKind::JumpOutOfBounds);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    /// Follow every path through the chunk, tracking how many values are on
+    /// the stack in this function's frame.
+    fn check_stack(&mut self, starts: &[usize]) {
+        let mut depths: Vec<Option<usize>> = vec![None; self.chunk.code.len()];
+        // Slot zero holds the function or receiver, followed by the arguments.
+        let mut pending = vec![(starts[0], self.arity + 1)];
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(expected) if expected != depth => {
+                    self.error(
+                        offset,
+                        VerifyErrorKind::InconsistentStackDepth {
+                            expected,
+                            found: depth,
+                        },
+                    );
+                    continue;
+                }
+                Some(_) => continue,
+                None => depths[offset] = Some(depth),
+            }
+
+            let (op, size) = self.chunk.decode(offset);
+            let (pops, pushes) = stack_effect(&op);
+            if depth < pops {
+                self.error(offset, VerifyErrorKind::StackUnderflow);
+                continue;
+            }
+            match &op {
+                Op::GetLocal { idx } | Op::SetLocal { idx } if *idx as usize >= depth => {
+                    self.error(offset, VerifyErrorKind::LocalOutOfRange(*idx));
+                    continue;
+                }
+                Op::GetUpvalue { idx } | Op::SetUpvalue { idx }
+                    if *idx as usize >= self.upvalue_count =>
+                {
+                    self.error(offset, VerifyErrorKind::UpvalueOutOfRange(*idx));
+                    continue;
+                }
+                Op::Closure { upvalues, .. } => {
+                    for upvalue in upvalues {
+                        let (index, limit) = (upvalue.index as usize, self.upvalue_count);
+                        if upvalue.is_local && index >= depth {
+                            self.error(offset, VerifyErrorKind::LocalOutOfRange(upvalue.index));
+                        } else if !upvalue.is_local && index >= limit {
+                            self.error(offset, VerifyErrorKind::UpvalueOutOfRange(upvalue.index));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            let depth = depth - pops + pushes;
+            let next = offset + size;
+            match op {
+                Op::Return => {}
+                Op::Jump { offset: jump } => pending.push((next + jump as usize, depth)),
+                Op::Loop { offset: jump } => pending.push((next - jump as usize, depth)),
+                Op::JumpIfFalse { offset: jump } => {
+                    pending.push((next + jump as usize, depth));
+                    pending.push((next, depth));
+                }
+                Op::ForIter { offset: jump } => {
+                    pending.push((next + jump as usize, depth - 1));
+                    pending.push((next, depth));
+                }
+                // The last instruction is a return, so there is always a next.
+                _ => pending.push((next, depth)),
+            }
+        }
+    }
+
+    fn error(&mut self, offset: usize, kind: VerifyErrorKind) {
+        self.errors.push(VerifyError {
+            function: self.function.clone(),
+            offset,
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+fn chunk_of(ops: Vec<Op>) -> Chunk {
+    let mut chunk = Chunk::new();
+    for op in ops {
+        chunk.push_op_code(op, 1, 1, 1);
+    }
+    chunk
+}
+
+#[cfg(test)]
+fn error_kinds(chunk: &Chunk) -> Vec<(usize, VerifyErrorKind)> {
+    match chunk.verify() {
+        Ok(()) => vec![],
+        Err(errors) => errors.into_iter().map(|e| (e.offset, e.kind)).collect(),
+    }
+}
+
+#[rstest]
+#[case::valid(
+    vec![Op::Constant { value: Value::Number(1.0) }, Op::Print, Op::Nil, Op::Return],
+    vec![]
+)]
+#[case::missing_return(vec![Op::Nil, Op::Pop], vec![(1, VerifyErrorKind::MissingReturn)])]
+#[case::empty(vec![], vec![(0, VerifyErrorKind::MissingReturn)])]
+#[case::underflow(vec![Op::Add, Op::Return], vec![(0, VerifyErrorKind::StackUnderflow)])]
+#[case::call_underflow(
+    vec![Op::Call { arg_count: 1 }, Op::Return],
+    vec![(0, VerifyErrorKind::StackUnderflow)]
+)]
+#[case::local_out_of_range(
+    vec![Op::GetLocal { idx: 1 }, Op::Return],
+    vec![(0, VerifyErrorKind::LocalOutOfRange(1))]
+)]
+#[case::upvalue_in_script(
+    vec![Op::GetUpvalue { idx: 0 }, Op::Return],
+    vec![(0, VerifyErrorKind::UpvalueOutOfRange(0))]
+)]
+#[case::jump_out_of_bounds(
+    vec![Op::Jump { offset: 10 }, Op::Nil, Op::Return],
+    vec![(0, VerifyErrorKind::JumpOutOfBounds)]
+)]
+#[case::loop_before_start(
+    vec![Op::Loop { offset: 4 }, Op::Nil, Op::Return],
+    vec![(0, VerifyErrorKind::JumpOutOfBounds)]
+)]
+#[case::jump_into_instruction(
+    vec![Op::Jump { offset: 1 }, Op::Jump { offset: 0 }, Op::Nil, Op::Return],
+    vec![(0, VerifyErrorKind::JumpIntoInstruction(4))]
+)]
+#[case::inconsistent_depth(
+    vec![Op::True, Op::JumpIfFalse { offset: 1 }, Op::Nil, Op::Return],
+    vec![(5, VerifyErrorKind::InconsistentStackDepth { expected: 3, found: 2 })]
+)]
+#[case::for_iter_exit_has_no_item(
+    vec![Op::Nil, Op::ForIter { offset: 0 }, Op::Return],
+    vec![(4, VerifyErrorKind::InconsistentStackDepth { expected: 3, found: 2 })]
+)]
+fn verify_checks_ops(#[case] ops: Vec<Op>, #[case] expected: Vec<(usize, VerifyErrorKind)>) {
+    assert_eq!(error_kinds(&chunk_of(ops)), expected);
+}
+
+#[rstest]
+fn verify_checks_encoding() {
+    let mut chunk = chunk_of(vec![Op::Nil, Op::Return]);
+    chunk.code[0] = 0xFF;
+    assert_eq!(
+        error_kinds(&chunk),
+        vec![(0, VerifyErrorKind::InvalidOpCode(0xFF))]
+    );
+
+    // A constant instruction missing its operand.
+    let mut chunk = chunk_of(vec![Op::Nil, Op::Return]);
+    chunk.code = vec![0, 1];
+    assert_eq!(error_kinds(&chunk), vec![(1, VerifyErrorKind::Truncated)]);
+
+    // OP_GET_GLOBAL with a missing constant, then with a number as its name.
+    let mut chunk = chunk_of(vec![Op::Nil, Op::Nil, Op::Return]);
+    chunk.code = vec![19, 0, 0];
+    assert_eq!(
+        error_kinds(&chunk),
+        vec![(0, VerifyErrorKind::ConstantOutOfRange(0))]
+    );
+    chunk.constants.push(Value::Number(1.0));
+    assert_eq!(
+        error_kinds(&chunk),
+        vec![(
+            0,
+            VerifyErrorKind::WrongConstantType {
+                index: 0,
+                expected: "string"
+            }
+        )]
+    );
+
+    let mut chunk = chunk_of(vec![Op::Nil, Op::Return]);
+    chunk.line_nos = vec![(1, 1)];
+    assert_eq!(
+        error_kinds(&chunk),
+        vec![(0, VerifyErrorKind::LineTableMismatch)]
+    );
+}
+
+#[rstest]
+fn verify_checks_nested_functions() {
+    let mut heap = crate::memory::Heap::new();
+    let mut function = crate::object::Function::new(Some("f".to_string()));
+    function.arity = 1;
+    function.upvalue_count = 1;
+    function.chunk = chunk_of(vec![
+        Op::GetLocal { idx: 1 },
+        Op::GetUpvalue { idx: 0 },
+        Op::GetLocal { idx: 4 },
+        Op::Return,
+    ]);
+    let function = heap.alloc(function);
+    let chunk = chunk_of(vec![
+        Op::Nil,
+        Op::Closure {
+            function,
+            upvalues: vec![super::UpvalueIndex {
+                is_local: true,
+                index: 2,
+            }],
+        },
+        Op::Return,
+    ]);
+    assert_eq!(
+        chunk.verify(),
+        Err(vec![
+            VerifyError {
+                function: Some("f".to_string()),
+                offset: 4,
+                kind: VerifyErrorKind::LocalOutOfRange(4),
+            },
+            VerifyError {
+                function: None,
+                offset: 1,
+                kind: VerifyErrorKind::LocalOutOfRange(2),
+            },
+        ])
+    );
+}
+
+#[rstest]
+fn verify_limits_function_nesting() {
+    let mut heap = crate::memory::Heap::new();
+    let mut function = crate::object::Function::new(Some("f".to_string()));
+    function.chunk = chunk_of(vec![Op::Nil, Op::Return]);
+    let mut function = heap.alloc(function);
+    for _ in 0..100_000 {
+        let mut outer = crate::object::Function::new(Some("f".to_string()));
+        outer.chunk = chunk_of(vec![
+            Op::Closure {
+                function,
+                upvalues: vec![],
+            },
+            Op::Return,
+        ]);
+        function = heap.alloc(outer);
+    }
+    assert_eq!(
+        chunk_of(vec![Op::Closure { function, upvalues: vec![] }, Op::Return]).verify(),
+        Err(vec![VerifyError {
+            function: Some("f".to_string()),
+            offset: 0,
+            kind: VerifyErrorKind::NestedTooDeeply,
+        }])
+    );
+}
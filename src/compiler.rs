@@ -1,54 +1,89 @@
-use std::mem::size_of;
+use std::mem;
+use std::rc::Rc;
 
-use crate::chunk::{Chunk, Op};
+use crate::chunk::{Chunk, Op, UpvalueIndex};
+use crate::diagnostic::Diagnostic;
+use crate::memory::{Gc, Heap, Trace, Tracer};
 use crate::object::{Function, Object};
-use crate::scanner::{Scanner, Token, TokenData};
+use crate::scanner::{self, Scanner, Token, TokenData};
 use crate::strings::Strings;
 use crate::value::Value;
 
-pub fn compile(source: &str) -> Result<(Function, Vec<Object>, Strings), ()> {
-    let mut parser = Parser::new(source);
-    if cfg!(feature = "trace") {
-        parser.current_chunk().disassemble("chunk".to_string());
-    }
-
+/// Compile `source` into a script function. `mark_roots` marks any objects
+/// outside the compiler that must survive a collection during compilation.
+/// On failure every diagnostic reported is returned, in source order.
+///
+/// With `repl`, top-level expression statements print their value and the
+/// last one may leave off its `;`.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    strings: &mut Strings,
+    mark_roots: &dyn Fn(&mut Tracer),
+    repl: bool,
+) -> Result<Gc<Function>, Vec<Diagnostic>> {
+    let mut parser = Parser::new(source, heap, strings, mark_roots);
+    parser.repl = repl;
     while !parser.match_(Token::Eof) {
         parser.declaration();
     }
     parser.consume(Token::Eof, "Expected EOF".to_string());
-    parser.end_compiler();
-    if parser.had_error {
-        Err(())
+    let (function, _) = parser.end_compiler();
+    if !parser.diagnostics.is_empty() {
+        Err(parser.diagnostics)
     } else {
-        Ok((parser.compiler.function, parser.objects, parser.strings))
+        Ok(parser.heap.alloc(function))
     }
 }
 
 struct Parser<'a> {
+    /// Shared with every chunk compiled from it.
+    source: Rc<str>,
     scanner: Scanner<'a>,
     compiler: Compiler,
+    classes: Vec<ClassCompiler>,
     prev_token: TokenData<'a>,
+    /// Every object created while compiling. These are treated as roots until
+    /// the finished script function takes ownership of them.
     objects: Vec<Object>,
-    strings: Strings,
-    had_error: bool,
+    heap: &'a mut Heap,
+    strings: &'a mut Strings,
+    mark_roots: &'a dyn Fn(&mut Tracer),
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    /// Whether to print the value of top-level expression statements.
+    repl: bool,
 }
 
 impl<'a> Parser<'a> {
-    fn new(source: &'a str) -> Parser<'a> {
+    fn new(
+        source_text: &'a str,
+        heap: &'a mut Heap,
+        strings: &'a mut Strings,
+        mark_roots: &'a dyn Fn(&mut Tracer),
+    ) -> Parser<'a> {
+        let source: Rc<str> = Rc::from(source_text);
+        let mut compiler = Compiler::new(FunctionType::Script, None);
+        compiler.function.chunk.source = Rc::clone(&source);
         Parser {
-            scanner: Scanner::new(source),
-            compiler: Compiler::new(),
+            source,
+            scanner: Scanner::new(source_text),
+            compiler,
+            classes: vec![],
             objects: vec![],
-            strings: Strings::new(),
+            heap,
+            strings,
+            mark_roots,
             prev_token: TokenData {
                 token: Token::Sof,
                 line: 0,
+                column: 0,
                 source: "",
                 start: 0,
             },
-            had_error: false,
+            diagnostics: vec![],
             panic_mode: false,
+            repl: false,
         }
     }
 
@@ -58,9 +93,7 @@ impl<'a> Parser<'a> {
             let token_data = self.scanner.peek();
             match token_data.token {
                 Token::Error(error_type) => {
-                    self.had_error = true;
-                    self.panic_mode = true;
-                    error_at(&token_data, error_type.as_string());
+                    self.error_at(token_data, error_type.as_string());
                     self.scanner.next();
                 }
                 _ => {
@@ -71,16 +104,28 @@ impl<'a> Parser<'a> {
     }
 
     fn error(&mut self, message: String) {
-        self.had_error = true;
-        self.panic_mode = true;
-        error_at(&self.prev_token, message)
+        self.error_at(self.prev_token, message)
     }
 
     fn error_at_current(&mut self, message: String) {
-        self.had_error = true;
-        self.panic_mode = true;
         let token_data = self.scanner.peek();
-        error_at(&token_data, message)
+        self.error_at(token_data, message)
+    }
+
+    /// Record a diagnostic at `token_data`, unless we are already recovering
+    /// from an earlier error, in which case it is most likely a cascade.
+    fn error_at(&mut self, token_data: TokenData, message: String) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let span = token_data.start..token_data.start + token_data.source.len();
+        self.diagnostics.push(Diagnostic::error(
+            token_data.line,
+            token_data.column,
+            span,
+            message,
+        ));
     }
 
     fn expression(&mut self) {
@@ -97,6 +142,123 @@ impl<'a> Parser<'a> {
         self.consume(Token::RightBrace, "Expect '}' after block.".to_string());
     }
 
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.prev_token.source.to_string();
+        self.begin_compiler(function_type, Some(name));
+        self.begin_scope();
+
+        self.consume(
+            Token::LeftParen,
+            "Expect '(' after function name.".to_string(),
+        );
+        if self.scanner.peek().token != Token::RightParen {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.".to_string());
+                }
+                let constant = self.parse_variable("Expect parameter name.".to_string());
+                self.define_variable(constant);
+                if !self.match_(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            Token::RightParen,
+            "Expect ')' after parameters.".to_string(),
+        );
+        self.consume(
+            Token::LeftBrace,
+            "Expect '{' before function body.".to_string(),
+        );
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.alloc(function);
+        self.objects.push(Object::Function(function));
+        self.collect_garbage_if_needed();
+        self.emit_byte(Op::Closure { function, upvalues });
+    }
+
+    fn method(&mut self) {
+        self.consume(Token::Identifier, "Expect method name.".to_string());
+        let name = self.identifier_constant(self.prev_token);
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        let name = self.intern(name);
+        self.emit_byte(Op::Method { name });
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(Token::Identifier, "Expect class name.".to_string());
+        let class_name = self.prev_token;
+        let name = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        let interned_name = self.intern(name.clone());
+        self.emit_byte(Op::Class {
+            name: interned_name,
+        });
+        self.define_variable(name);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_(Token::Less) {
+            self.consume(Token::Identifier, "Expect superclass name.".to_string());
+            self.variable(false);
+            if class_name.source == self.prev_token.source {
+                self.error("A class can't inherit from itself.".to_string());
+            }
+
+            // Each subclass gets its own scope holding `super`, so methods
+            // capture the right superclass as an upvalue.
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable("".to_string());
+
+            self.named_variable(class_name, false);
+            self.emit_byte(Op::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so methods can be bound to it.
+        self.named_variable(class_name, false);
+        self.consume(
+            Token::LeftBrace,
+            "Expect '{' before class body.".to_string(),
+        );
+        while self.scanner.peek().token != Token::RightBrace
+            && self.scanner.peek().token != Token::Eof
+        {
+            self.method();
+        }
+        self.consume(
+            Token::RightBrace,
+            "Expect '}' after class body.".to_string(),
+        );
+        self.emit_byte(Op::Pop);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.".to_string());
+        // A function may refer to itself, so it is usable before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.".to_string());
 
@@ -115,6 +277,16 @@ impl<'a> Parser<'a> {
 
     fn expression_statement(&mut self) {
         self.expression();
+        if self.repl
+            && self.compiler.function_type == FunctionType::Script
+            && self.compiler.scope_depth == 0
+        {
+            if self.scanner.peek().token != Token::Eof {
+                self.consume(Token::Semicolon, "Expect ';' after expression.".to_string());
+            }
+            self.emit_byte(Op::Print);
+            return;
+        }
         self.consume(Token::Semicolon, "Expect ';' after expression.".to_string());
         self.emit_byte(Op::Pop)
     }
@@ -122,6 +294,11 @@ impl<'a> Parser<'a> {
     fn for_statement(&mut self) {
         self.begin_scope();
         self.consume(Token::LeftParen, "Expect '(' after 'for'.".to_string());
+        if self.for_in_ahead() {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
         if self.match_(Token::Semicolon) {
             // No initializer.
         } else if self.match_(Token::Var) {
@@ -156,6 +333,7 @@ impl<'a> Parser<'a> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
@@ -163,9 +341,55 @@ impl<'a> Parser<'a> {
             self.patch_jump(exit_jump);
             self.emit_byte(Op::Pop);
         }
+        self.end_loop();
         self.end_scope();
     }
 
+    /// Whether the `for` clauses ahead are `var name in`, with the `var`
+    /// optional.
+    fn for_in_ahead(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        if scanner.peek().token == Token::Var {
+            scanner.next();
+        }
+        scanner.next().token == Token::Identifier && scanner.next().token == Token::In
+    }
+
+    /// The rest of `for (var name in iterable) body`. The iterator sits in a
+    /// hidden local for the whole loop, and each item is pushed as a new
+    /// `name` local for one run of the body, so closures capture the item
+    /// they were created with.
+    fn for_in_statement(&mut self) {
+        self.match_(Token::Var);
+        self.consume(Token::Identifier, "Expect variable name.".to_string());
+        let name = self.prev_token;
+        self.consume(Token::In, "Expect 'in' after variable name.".to_string());
+        let in_token = self.prev_token;
+        self.expression();
+        self.consume(
+            Token::RightParen,
+            "Expect ')' after for clauses.".to_string(),
+        );
+        self.emit_byte_at(Op::GetIter, in_token);
+        // The space keeps the iterator from being named in the body.
+        self.add_local(" iterator".to_string());
+        self.mark_initialized();
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_byte_at(Op::ForIter { offset: 0xFFFF }, in_token);
+        let exit_jump = self.current_chunk().code.len() - 2;
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        self.add_local(name.source.to_string());
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.end_loop();
+    }
+
     fn if_statement(&mut self) {
         self.consume(Token::LeftParen, "Expect '(' after 'if'.".to_string());
         self.expression();
@@ -187,7 +411,11 @@ impl<'a> Parser<'a> {
     }
 
     fn declaration(&mut self) {
-        if self.match_(Token::Var) {
+        if self.match_(Token::Class) {
+            self.class_declaration();
+        } else if self.match_(Token::Fun) {
+            self.fun_declaration();
+        } else if self.match_(Token::Var) {
             self.var_declaration();
         } else {
             self.statement();
@@ -205,8 +433,14 @@ impl<'a> Parser<'a> {
             self.for_statement();
         } else if self.match_(Token::If) {
             self.if_statement();
+        } else if self.match_(Token::Return) {
+            self.return_statement();
         } else if self.match_(Token::While) {
             self.while_statement();
+        } else if self.match_(Token::Break) {
+            self.break_statement();
+        } else if self.match_(Token::Continue) {
+            self.continue_statement();
         } else if self.match_(Token::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -222,6 +456,26 @@ impl<'a> Parser<'a> {
         self.emit_byte(Op::Print);
     }
 
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.".to_string());
+        }
+
+        if self.match_(Token::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.".to_string());
+            }
+            self.expression();
+            self.consume(
+                Token::Semicolon,
+                "Expect ';' after return value.".to_string(),
+            );
+            self.emit_byte(Op::Return);
+        }
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
 
@@ -231,21 +485,67 @@ impl<'a> Parser<'a> {
 
         let exit_jump = self.emit_jump(Op::JumpIfFalse { offset: 0xFFFF });
         self.emit_byte(Op::Pop);
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(Op::Pop);
+        self.end_loop();
+    }
+
+    fn break_statement(&mut self) {
+        if self.compiler.loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.".to_string());
+        }
+        self.consume(Token::Semicolon, "Expect ';' after 'break'.".to_string());
+
+        if let Some(loop_) = self.compiler.loops.last() {
+            self.discard_locals(loop_.scope_depth);
+            let jump = self.emit_jump(Op::Jump { offset: 0xFFFF });
+            self.compiler.loops.last_mut().unwrap().breaks.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        if self.compiler.loops.is_empty() {
+            self.error("Can't use 'continue' outside of a loop.".to_string());
+        }
+        self.consume(Token::Semicolon, "Expect ';' after 'continue'.".to_string());
+
+        if let Some(loop_) = self.compiler.loops.last() {
+            let start = loop_.start;
+            self.discard_locals(loop_.scope_depth);
+            self.emit_loop(start);
+        }
+    }
+
+    /// Start compiling the body of a loop that `continue` takes back to
+    /// `start`.
+    fn begin_loop(&mut self, start: usize) {
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth: self.compiler.scope_depth,
+            breaks: vec![],
+        });
+    }
+
+    /// Finish a loop, sending its `break`s to the current end of the chunk.
+    fn end_loop(&mut self) {
+        let loop_ = self.compiler.loops.pop().unwrap();
+        for jump in loop_.breaks {
+            self.patch_jump(jump);
+        }
     }
 
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
         while self.scanner.peek().token != Token::Eof {
-            if self.scanner.peek().token == Token::Semicolon {
+            if self.prev_token.token == Token::Semicolon {
                 return;
             }
-            match self.prev_token.token {
+            match self.scanner.peek().token {
                 Token::Class => {
                     return;
                 }
@@ -285,36 +585,151 @@ impl<'a> Parser<'a> {
     }
 
     fn unary(&mut self) {
-        let op_type = self.prev_token.token;
+        let operator = self.prev_token;
+        let op_type = operator.token;
         self.expression();
         match op_type {
             Token::Minus => {
-                self.emit_byte(Op::Negate);
+                self.emit_byte_at(Op::Negate, operator);
             }
-            Token::Bang => self.emit_byte(Op::Not),
+            Token::Bang => self.emit_byte_at(Op::Not, operator),
             _ => panic!("Unexpected token: {:?}!", op_type),
         }
     }
 
     fn binary(&mut self) {
-        let op_type = self.prev_token.token;
+        // Errors in the emitted instructions should point at the operator,
+        // not the end of the right operand.
+        let operator = self.prev_token;
+        let op_type = operator.token;
         let precedence = op_type.get_precedence();
         self.parse_precedence((precedence as usize) + 1);
         match op_type {
-            Token::Plus => self.emit_byte(Op::Add),
-            Token::Minus => self.emit_byte(Op::Subtract),
-            Token::Star => self.emit_byte(Op::Multiply),
-            Token::Slash => self.emit_byte(Op::Divide),
-            Token::BangEqual => self.emit_bytes(Op::Equal, Op::Not),
-            Token::EqualEqual => self.emit_byte(Op::Equal),
-            Token::Greater => self.emit_byte(Op::Greater),
-            Token::GreaterEqual => self.emit_bytes(Op::Less, Op::Not),
-            Token::Less => self.emit_byte(Op::Less),
-            Token::LessEqual => self.emit_bytes(Op::Greater, Op::Not),
+            Token::Plus => self.emit_byte_at(Op::Add, operator),
+            Token::Minus => self.emit_byte_at(Op::Subtract, operator),
+            Token::Star => self.emit_byte_at(Op::Multiply, operator),
+            Token::Slash => self.emit_byte_at(Op::Divide, operator),
+            Token::BangEqual => {
+                self.emit_byte_at(Op::Equal, operator);
+                self.emit_byte_at(Op::Not, operator);
+            }
+            Token::EqualEqual => self.emit_byte_at(Op::Equal, operator),
+            Token::Greater => self.emit_byte_at(Op::Greater, operator),
+            Token::GreaterEqual => {
+                self.emit_byte_at(Op::Less, operator);
+                self.emit_byte_at(Op::Not, operator);
+            }
+            Token::Less => self.emit_byte_at(Op::Less, operator),
+            Token::LessEqual => {
+                self.emit_byte_at(Op::Greater, operator);
+                self.emit_byte_at(Op::Not, operator);
+            }
             _ => panic!("Unexpected token: {:?}!", self.prev_token.token),
         }
     }
 
+    fn call(&mut self) {
+        let paren = self.prev_token;
+        let arg_count = self.argument_list();
+        self.emit_byte_at(Op::Call { arg_count }, paren);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if self.scanner.peek().token != Token::RightParen {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.".to_string());
+                } else {
+                    arg_count += 1;
+                }
+                if !self.match_(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightParen, "Expect ')' after arguments.".to_string());
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(
+            Token::Identifier,
+            "Expect property name after '.'.".to_string(),
+        );
+        let name = self.identifier_constant(self.prev_token);
+        let name = self.intern(name);
+
+        if can_assign && self.match_(Token::Equal) {
+            self.expression();
+            self.emit_byte(Op::SetProperty { name });
+        } else {
+            self.emit_byte(Op::GetProperty { name });
+        }
+    }
+
+    fn list(&mut self) {
+        let bracket = self.prev_token;
+        let mut item_count: usize = 0;
+        while self.scanner.peek().token != Token::RightBracket {
+            self.expression();
+            if item_count == u8::MAX as usize {
+                self.error("Can't have more than 255 items in a list literal.".to_string());
+            } else {
+                item_count += 1;
+            }
+            if !self.match_(Token::Comma) {
+                break;
+            }
+        }
+        self.consume(Token::RightBracket, "Expect ']' after list items.".to_string());
+        self.emit_byte_at(
+            Op::BuildList {
+                item_count: item_count as u8,
+            },
+            bracket,
+        );
+    }
+
+    fn map(&mut self) {
+        let brace = self.prev_token;
+        let mut entry_count: usize = 0;
+        while self.scanner.peek().token != Token::RightBrace {
+            self.expression();
+            self.consume(Token::Colon, "Expect ':' after map key.".to_string());
+            self.expression();
+            if entry_count == u8::MAX as usize {
+                self.error("Can't have more than 255 entries in a map literal.".to_string());
+            } else {
+                entry_count += 1;
+            }
+            if !self.match_(Token::Comma) {
+                break;
+            }
+        }
+        self.consume(Token::RightBrace, "Expect '}' after map entries.".to_string());
+        self.emit_byte_at(
+            Op::BuildMap {
+                entry_count: entry_count as u8,
+            },
+            brace,
+        );
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        let bracket = self.prev_token;
+        self.expression();
+        self.consume(Token::RightBracket, "Expect ']' after index.".to_string());
+
+        if can_assign && self.match_(Token::Equal) {
+            self.expression();
+            self.emit_byte_at(Op::SetIndex, bracket);
+        } else {
+            self.emit_byte_at(Op::GetIndex, bracket);
+        }
+    }
+
     fn number(&mut self) {
         let value = self.prev_token.source.parse::<f64>().unwrap();
         self.emit_constant(Value::Number(value));
@@ -349,12 +764,60 @@ impl<'a> Parser<'a> {
     }
 
     fn string(&mut self) {
-        let string_data = self.prev_token.source[1..self.prev_token.source.len() - 1].to_string();
-        let object = Object::String {
-            chars: self.strings.new_string(string_data),
-        };
-        self.objects.push(object);
-        self.emit_constant(Value::Obj(self.objects.last().unwrap().clone()));
+        let token = self.prev_token;
+        match scanner::unescape(&token.source[1..token.source.len() - 1]) {
+            Ok(string) => {
+                let chars = self.intern(string);
+                self.emit_constant(Value::Obj(Object::String { chars }));
+            }
+            Err((range, message)) => {
+                // Point at the escape itself unless the string has a line
+                // break before it, which the token's column doesn't cover.
+                let before = &token.source[..range.start + 1];
+                let escape = if before.contains('\n') {
+                    token
+                } else {
+                    TokenData {
+                        column: token.column + before.chars().count(),
+                        source: &token.source[range.start + 1..range.end + 1],
+                        start: token.start + range.start + 1,
+                        ..token
+                    }
+                };
+                self.error_at(escape, message);
+            }
+        }
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class.".to_string()),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.".to_string())
+            }
+            Some(_) => {}
+        }
+
+        let super_token = self.prev_token;
+        self.consume(Token::Dot, "Expect '.' after 'super'.".to_string());
+        self.consume(
+            Token::Identifier,
+            "Expect superclass method name.".to_string(),
+        );
+        let name = self.identifier_constant(self.prev_token);
+        let name = self.intern(name);
+
+        self.named_variable(synthetic_token(Token::This, "this", super_token), false);
+        self.named_variable(synthetic_token(Token::Super, "super", super_token), false);
+        self.emit_byte(Op::GetSuper { name });
+    }
+
+    fn this_(&mut self) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.".to_string());
+            return;
+        }
+        self.variable(false);
     }
 
     fn variable(&mut self, can_assign: bool) {
@@ -365,14 +828,17 @@ impl<'a> Parser<'a> {
         let get_op;
         let set_op;
 
-        if let Some(idx) = self.resolve_local(&name.token) {
+        if let Some(idx) = self.resolve_local(name.source) {
             get_op = Op::GetLocal { idx };
             set_op = Op::SetLocal { idx };
+        } else if let Some(idx) = self.resolve_upvalue(name.source) {
+            get_op = Op::GetUpvalue { idx };
+            set_op = Op::SetUpvalue { idx };
         } else {
             let arg = self.identifier_constant(name);
-            let name = self.strings.new_string(arg);
-            get_op = Op::GetGlobal { name: name.clone() };
-            set_op = Op::SetGlobal { name: name.clone() };
+            let name = self.intern(arg);
+            get_op = Op::GetGlobal { name };
+            set_op = Op::SetGlobal { name };
         }
 
         if can_assign && self.match_(Token::Equal) {
@@ -383,16 +849,22 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
-        for (i, local) in self.compiler.locals.iter().enumerate().rev() {
-            if &local.name == name {
-                if local.depth == None {
-                    self.error("Can't read local variable in its own initializer.".to_string());
-                }
-                return Some(i as u8);
+    fn resolve_local(&mut self, name: &str) -> Option<u8> {
+        let idx = self.compiler.resolve_local(name)?;
+        if self.compiler.locals[idx as usize].depth.is_none() {
+            self.error("Can't read local variable in its own initializer.".to_string());
+        }
+        Some(idx)
+    }
+
+    fn resolve_upvalue(&mut self, name: &str) -> Option<u8> {
+        match self.compiler.resolve_upvalue(name) {
+            Ok(idx) => idx,
+            Err(message) => {
+                self.error(message.to_string());
+                None
             }
         }
-        return None;
     }
 
     fn consume(&mut self, expected_token: Token, message: String) {
@@ -413,8 +885,14 @@ impl<'a> Parser<'a> {
     }
 
     fn emit_byte(&mut self, op: Op) {
-        let line = self.prev_token.line;
-        self.current_chunk().push_op_code(op, line)
+        self.emit_byte_at(op, self.prev_token)
+    }
+
+    /// Emit `op`, attributing it to `token` in the line and column tables.
+    fn emit_byte_at(&mut self, op: Op, token: TokenData) {
+        let width = token.source.chars().count();
+        self.current_chunk()
+            .push_op_code(op, token.line, token.column, width);
     }
 
     fn emit_bytes(&mut self, op_1: Op, op_2: Op) {
@@ -452,15 +930,33 @@ impl<'a> Parser<'a> {
         self.current_chunk().code[offset + 1] = (jump >> 8) as u8;
     }
 
-    fn end_compiler(&mut self) {
-        self.emit_byte(Op::Return);
-        if cfg!(feature = "trace") {
-            let name = self.compiler.function.name.clone();
-            let name = name.unwrap_or("<script>".to_string());
-            self.current_chunk().disassemble(name);
+    fn emit_return(&mut self) {
+        if self.compiler.function_type == FunctionType::Initializer {
+            // Initializers always return the instance in slot zero.
+            self.emit_bytes(Op::GetLocal { idx: 0 }, Op::Return);
+        } else {
+            self.emit_bytes(Op::Nil, Op::Return);
         }
     }
 
+    fn begin_compiler(&mut self, function_type: FunctionType, name: Option<String>) {
+        let enclosing = mem::replace(&mut self.compiler, Compiler::new(function_type, name));
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        self.compiler.function.chunk.source = Rc::clone(&self.source);
+    }
+
+    fn end_compiler(&mut self) -> (Function, Vec<UpvalueIndex>) {
+        self.emit_return();
+        let compiler = match self.compiler.enclosing.take() {
+            Some(enclosing) => mem::replace(&mut self.compiler, *enclosing),
+            None => mem::replace(
+                &mut self.compiler,
+                Compiler::new(FunctionType::Script, None),
+            ),
+        };
+        (compiler.function, compiler.upvalues)
+    }
+
     fn begin_scope(&mut self) {
         self.compiler.scope_depth += 1;
     }
@@ -468,12 +964,31 @@ impl<'a> Parser<'a> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        while let Some(local) = self.compiler.locals.last()
-            && let Some(depth) = local.depth && depth > self.compiler.scope_depth
-        {
-            self.emit_byte(Op::Pop);
-            self.compiler.locals.pop();
+        let count = self.discard_locals(self.compiler.scope_depth);
+        let len = self.compiler.locals.len();
+        self.compiler.locals.truncate(len - count);
+    }
+
+    /// Emit code to pop the locals declared deeper than `scope_depth`,
+    /// closing any that are captured, and return how many there are. The
+    /// compiler still sees them, as when jumping out of a loop body.
+    fn discard_locals(&mut self, scope_depth: usize) -> usize {
+        let discarded: Vec<bool> = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| matches!(local.depth, Some(depth) if depth > scope_depth))
+            .map(|local| local.is_captured)
+            .collect();
+        for &is_captured in &discarded {
+            if is_captured {
+                self.emit_byte(Op::CloseUpvalue);
+            } else {
+                self.emit_byte(Op::Pop);
+            }
         }
+        discarded.len()
     }
 
     fn parse_precedence(&mut self, precedence: usize) {
@@ -490,6 +1005,10 @@ impl<'a> Parser<'a> {
             Token::Bang => self.unary(),
             Token::String => self.string(),
             Token::Identifier => self.variable(can_assign),
+            Token::This => self.this_(),
+            Token::Super => self.super_(),
+            Token::LeftBracket => self.list(),
+            Token::LeftBrace => self.map(),
             _ => self.error("Expect expression".to_string()),
         }
 
@@ -508,6 +1027,9 @@ impl<'a> Parser<'a> {
                 Token::LessEqual => self.binary(),
                 Token::And => self.and(),
                 Token::Or => self.or(),
+                Token::LeftParen => self.call(),
+                Token::Dot => self.dot(can_assign),
+                Token::LeftBracket => self.index(can_assign),
                 _ => self.error("Expect expression".to_string()),
             }
         }
@@ -529,6 +1051,9 @@ impl<'a> Parser<'a> {
     }
 
     fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
         self.compiler.locals.last_mut().unwrap().depth = Some(self.compiler.scope_depth);
     }
 
@@ -538,7 +1063,7 @@ impl<'a> Parser<'a> {
             return;
         }
 
-        let string = self.strings.new_string(name);
+        let string = self.intern(name);
         self.emit_byte(Op::DefineGlobal { name: string })
     }
 
@@ -555,9 +1080,13 @@ impl<'a> Parser<'a> {
         token_data.source.to_string()
     }
 
-    fn add_local(&mut self, name: Token) {
-        let local = Local { name, depth: None };
-        if self.compiler.locals.len() >= size_of::<u8>() {
+    fn add_local(&mut self, name: String) {
+        let local = Local {
+            name,
+            depth: None,
+            is_captured: false,
+        };
+        if self.compiler.locals.len() > u8::MAX as usize {
             self.error("Too many locals".to_string());
         } else {
             self.compiler.locals.push(local);
@@ -569,7 +1098,7 @@ impl<'a> Parser<'a> {
             return;
         }
 
-        let name = self.prev_token.token;
+        let name = self.prev_token.source;
         for local in self.compiler.locals.iter().rev() {
             if let Some(depth) = local.depth && depth < self.compiler.scope_depth {
                 break;
@@ -581,13 +1110,33 @@ impl<'a> Parser<'a> {
             }
         }
 
-        self.add_local(name);
+        self.add_local(name.to_string());
     }
 
     fn current_precedence(&mut self) -> Precedence {
         self.scanner.peek().token.get_precedence()
     }
 
+    fn intern(&mut self, string: String) -> Gc<String> {
+        let chars = self.strings.new_string(self.heap, string);
+        self.objects.push(Object::String { chars });
+        self.collect_garbage_if_needed();
+        chars
+    }
+
+    fn collect_garbage_if_needed(&mut self) {
+        if self.heap.should_collect() {
+            let objects = &self.objects;
+            let mark_roots = self.mark_roots;
+            self.heap.collect(self.strings, |tracer| {
+                mark_roots(tracer);
+                for object in objects {
+                    object.trace(tracer);
+                }
+            });
+        }
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
         &mut self.compiler.function.chunk
     }
@@ -628,42 +1177,121 @@ impl Token {
             Token::LessEqual => Precedence::Comparison,
             Token::And => Precedence::And,
             Token::Or => Precedence::Or,
+            Token::LeftParen => Precedence::Call,
+            Token::Dot => Precedence::Call,
+            Token::LeftBracket => Precedence::Call,
             _ => Precedence::None,
         }
     }
 }
 
-fn error_at(token_data: &TokenData, message: String) {
-    eprintln!(
-        "[line {}] Error at {}: {message}",
-        token_data.line, token_data.start
-    );
+/// A token for a name the compiler refers to implicitly, positioned at `at`.
+fn synthetic_token<'a>(token: Token, source: &'a str, at: TokenData<'a>) -> TokenData<'a> {
+    TokenData {
+        token,
+        source,
+        ..at
+    }
 }
 
 struct Compiler {
+    enclosing: Option<Box<Compiler>>,
     locals: Vec<Local>,
+    upvalues: Vec<UpvalueIndex>,
     scope_depth: usize,
+    /// The loops enclosing the code being compiled, innermost last.
+    loops: Vec<Loop>,
     function: Function,
     function_type: FunctionType,
 }
 
 impl Compiler {
-    fn new() -> Self {
+    fn new(function_type: FunctionType, name: Option<String>) -> Self {
+        // Slot zero holds the function being called, or the receiver in methods.
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
         Compiler {
-            locals: vec![],
+            enclosing: None,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: vec![],
             scope_depth: 0,
-            function: Function::new(None),
-            function_type: FunctionType::Script,
+            loops: vec![],
+            function: Function::new(name),
+            function_type,
         }
     }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        self.locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|idx| idx as u8)
+    }
+
+    /// Find `name` in an enclosing function, threading an upvalue through
+    /// every function between there and here.
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, &'static str> {
+        let enclosing = match &mut self.enclosing {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name) {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+        let upvalue = UpvalueIndex { is_local, index };
+        if let Some(idx) = self.upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(idx as u8);
+        }
+        if self.upvalues.len() > u8::MAX as usize {
+            return Err("Too many closure variables in function.");
+        }
+        self.upvalues.push(upvalue);
+        self.function.upvalue_count = self.upvalues.len();
+        Ok((self.upvalues.len() - 1) as u8)
+    }
 }
 
 struct Local {
-    name: Token,
+    name: String,
     depth: Option<usize>,
+    is_captured: bool,
+}
+
+/// A loop whose body is being compiled.
+struct Loop {
+    /// Where `continue` jumps back to: the condition, or the increment
+    /// clause of a `for` loop.
+    start: usize,
+    /// The scope depth outside the body. Locals deeper than this are
+    /// popped before jumping out of the body.
+    scope_depth: usize,
+    /// Offsets of the `break` jumps, patched at the end of the loop.
+    breaks: Vec<usize>,
 }
 
+#[derive(PartialEq)]
 enum FunctionType {
     Function,
+    Initializer,
+    Method,
     Script,
 }
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
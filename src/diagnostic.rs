@@ -0,0 +1,99 @@
+use std::{fmt, ops::Range};
+
+use rstest::rstest;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A problem found while compiling. `line` and `column` are 1-based and
+/// point at the start of the offending token; `span` is its byte range in
+/// the source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub line: u32,
+    pub column: usize,
+    pub span: Range<usize>,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    pub fn error(line: u32, column: usize, span: Range<usize>, message: String) -> Self {
+        Diagnostic {
+            line,
+            column,
+            span,
+            message,
+            severity: Severity::Error,
+        }
+    }
+
+    /// The diagnostic followed by a snippet of `source` underlining its span.
+    pub fn render(&self, source: &str) -> String {
+        let width = source
+            .get(self.span.clone())
+            .map_or(0, |text| text.chars().take_while(|&c| c != '\n').count());
+        format!("{self}\n{}", snippet(source, self.line, self.column, width))
+    }
+}
+
+/// Line `line` of `source` with `width` characters from `column` underlined
+/// `^~~~`, each indented and newline-terminated. The underline is at least
+/// one character wide and never runs past the end of the line. Empty if
+/// `source` has no such line.
+pub fn snippet(source: &str, line: u32, column: usize, width: usize) -> String {
+    let text = match source.lines().nth((line as usize).wrapping_sub(1)) {
+        Some(text) => text.trim_end_matches('\r'),
+        None => return String::new(),
+    };
+    // Keep tabs so the underline lines up however they are displayed.
+    let indent: String = text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let remaining = text
+        .chars()
+        .count()
+        .saturating_sub(column.saturating_sub(1));
+    let width = width.min(remaining).max(1);
+    format!("    {text}\n    {indent}^{}\n", "~".repeat(width - 1))
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.line, self.column, self.severity, self.message
+        )
+    }
+}
+
+#[rstest]
+#[case("a + b", 1, 3, 1, "    a + b\n      ^\n")]
+#[case("x\n\tfoo(bar);", 2, 2, 3, "    \tfoo(bar);\n    \t^~~\n")]
+#[case("abc", 1, 2, 10, "    abc\n     ^~\n")]
+#[case("abc", 1, 4, 0, "    abc\n       ^\n")]
+#[case("abc", 2, 1, 1, "")]
+fn snippet_underlines_span(
+    #[case] source: &str,
+    #[case] line: u32,
+    #[case] column: usize,
+    #[case] width: usize,
+    #[case] expected: &str,
+) {
+    assert_eq!(snippet(source, line, column, width), expected);
+}
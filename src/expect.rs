@@ -0,0 +1,197 @@
+//! Expectations written as comments in a Lox test script, in the format
+//! used by the Crafting Interpreters test suite:
+//!
+//! - `// expect: value` — the script prints `value` as its next line of
+//!   output.
+//! - `// expect runtime error: message` — the script stops with a runtime
+//!   error with this message, raised on the comment's line.
+//! - `// [line N] Error at 'x': message`, or `// Error at 'x': message` for
+//!   the comment's own line — compiling the script reports this error. `x`
+//!   is the text of the offending token, and errors at the end of the
+//!   script say `at end` instead.
+
+use rstest::rstest;
+
+use crate::vm::InterpretError;
+use crate::Diagnostic;
+
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+/// What a test script says it should do.
+#[derive(Debug, PartialEq, Default)]
+pub struct Expectations {
+    /// Each line of output, with the line of the script it is expected on.
+    output: Vec<(u32, String)>,
+    /// The message and line of the runtime error the script ends with.
+    runtime_error: Option<(String, u32)>,
+    /// Each compile error, formatted as in the comment.
+    compile_errors: Vec<String>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+        for (line_no, line) in (1..).zip(source.lines()) {
+            if let Some(idx) = line.find(EXPECT_OUTPUT) {
+                let value = &line[idx + EXPECT_OUTPUT.len()..];
+                expectations.output.push((line_no, value.to_string()));
+            } else if let Some(idx) = line.find(EXPECT_RUNTIME_ERROR) {
+                let message = &line[idx + EXPECT_RUNTIME_ERROR.len()..];
+                expectations.runtime_error = Some((message.to_string(), line_no));
+            } else if let Some(idx) = line.find("// [line ") {
+                expectations
+                    .compile_errors
+                    .push(line[idx + 3..].to_string());
+            } else if let Some(idx) = line.find("// Error") {
+                let error = &line[idx + 3..];
+                expectations
+                    .compile_errors
+                    .push(format!("[line {line_no}] {error}"));
+            }
+        }
+        expectations
+    }
+
+    /// Compare what running `source` printed and returned against these
+    /// expectations, describing each difference.
+    pub fn check(
+        &self,
+        source: &str,
+        output: &str,
+        result: &Result<(), InterpretError>,
+    ) -> Vec<String> {
+        let mut failures = vec![];
+
+        let mut actual = output.lines();
+        for (line_no, expected) in &self.output {
+            match actual.next() {
+                Some(line) if line == expected => {}
+                Some(line) => failures.push(format!(
+                    "Expected output '{expected}' on line {line_no} and got '{line}'."
+                )),
+                None => failures.push(format!(
+                    "Missing expected output '{expected}' on line {line_no}."
+                )),
+            }
+        }
+        for line in actual {
+            failures.push(format!("Got output '{line}' when none was expected."));
+        }
+
+        let diagnostics = match result {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics.as_slice(),
+            _ => &[],
+        };
+        let errors: Vec<String> = diagnostics
+            .iter()
+            .map(|diagnostic| format_error(source, diagnostic))
+            .collect();
+        for expected in &self.compile_errors {
+            if !errors.contains(expected) {
+                failures.push(format!("Missing expected error: {expected}"));
+            }
+        }
+        for error in &errors {
+            if !self.compile_errors.contains(error) {
+                failures.push(format!("Unexpected error: {error}"));
+            }
+        }
+
+        match (&self.runtime_error, result) {
+            (
+                Some((expected, line_no)),
+                Err(InterpretError::RuntimeError {
+                    message,
+                    stack_trace,
+                }),
+            ) => {
+                if message != expected {
+                    failures.push(format!(
+                        "Expected runtime error '{expected}' and got '{message}'."
+                    ));
+                }
+                let line = stack_trace.first().map_or(0, |frame| frame.line);
+                if line != *line_no {
+                    failures.push(format!(
+                        "Expected runtime error on line {line_no} but was on line {line}."
+                    ));
+                }
+            }
+            (Some((expected, _)), _) => {
+                failures.push(format!("Expected runtime error '{expected}' and got none."))
+            }
+            (None, Err(InterpretError::RuntimeError { message, .. })) => {
+                failures.push(format!("Unexpected runtime error '{message}'."));
+            }
+            (None, _) => {}
+        }
+
+        failures
+    }
+}
+
+/// `diagnostic` in the format of a compile error comment.
+fn format_error(source: &str, diagnostic: &Diagnostic) -> String {
+    let location = match source.get(diagnostic.span.clone()) {
+        Some(text) if !text.is_empty() => format!("at '{text}'"),
+        _ => "at end".to_string(),
+    };
+    format!(
+        "[line {}] {} {location}: {}",
+        diagnostic.line, diagnostic.severity, diagnostic.message
+    )
+}
+
+#[rstest]
+fn parse_reads_each_kind_of_comment() {
+    let source = "\
+print 1; // expect: 1
+print \"a b\"; // expect: \"a b\"
+var a = ; // Error at ';': Expect expression
+// [line 7] Error at end: Expect '}'
+nil(); // expect runtime error: Can only call functions and classes.
+";
+    assert_eq!(
+        Expectations::parse(source),
+        Expectations {
+            output: vec![(1, "1".to_string()), (2, "\"a b\"".to_string())],
+            runtime_error: Some(("Can only call functions and classes.".to_string(), 5)),
+            compile_errors: vec![
+                "[line 3] Error at ';': Expect expression".to_string(),
+                "[line 7] Error at end: Expect '}'".to_string(),
+            ],
+        }
+    );
+}
+
+#[rstest]
+#[case::output_matches("print 1; // expect: 1", "1\n", &[])]
+#[case::wrong_output(
+    "print 1; // expect: 1",
+    "2\n",
+    &["Expected output '1' on line 1 and got '2'."]
+)]
+#[case::missing_output(
+    "// expect: 1\n// expect: 2",
+    "1\n",
+    &["Missing expected output '2' on line 2."]
+)]
+#[case::extra_output("", "1\n", &["Got output '1' when none was expected."])]
+fn check_compares_output(#[case] source: &str, #[case] output: &str, #[case] expected: &[&str]) {
+    let expectations = Expectations::parse(source);
+    assert_eq!(expectations.check(source, output, &Ok(())), expected);
+}
+
+#[rstest]
+fn check_compares_compile_errors() {
+    let source = "var a = ; // Error at ';': Expect expression\nprint 1 +";
+    let result = Err(InterpretError::CompileError(vec![
+        Diagnostic::error(1, 9, 8..9, "Expect expression".to_string()),
+        Diagnostic::error(2, 10, 54..54, "Expect expression".to_string()),
+    ]));
+    assert_eq!(
+        Expectations::parse(source).check(source, "", &result),
+        ["Unexpected error: [line 2] Error at end: Expect expression"]
+    );
+}
@@ -0,0 +1,208 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use crate::memory::{Trace, Tracer};
+use crate::object::Object;
+use crate::value::Value;
+
+/// A Lox value held by the host: returned by a `Vm`, or passed to a native
+/// defined with `Vm::define_native`. While a handle to an object exists, the
+/// `Vm` that made it keeps the object alive.
+///
+/// Handles to numbers, booleans and nil, made with `From` or `Handle::nil`,
+/// work with any `Vm`. Any other handle may only be passed back to the `Vm`
+/// it came from, and only read while that `Vm` exists; doing otherwise
+/// panics.
+pub struct Handle {
+    value: Value,
+    /// Where an object is rooted, or `None` if the value isn't one.
+    root: Option<Root>,
+}
+
+/// An entry in a `Vm`'s table of handed out values, removed when the handle
+/// holding it is dropped.
+struct Root {
+    table: Rc<RefCell<Table>>,
+    key: usize,
+}
+
+#[derive(Default)]
+struct Table {
+    values: HashMap<usize, Value>,
+    next_key: usize,
+    /// Whether the `Vm` owning the table, and so its heap, still exists.
+    live: bool,
+}
+
+impl Table {
+    fn insert(&mut self, value: Value) -> usize {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.values.insert(key, value);
+        key
+    }
+}
+
+impl Handle {
+    pub fn nil() -> Handle {
+        Handle {
+            value: Value::Nil,
+            root: None,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self.value, Value::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self.value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The characters of a string, without the quotes it is displayed with.
+    pub fn string(&self) -> Option<String> {
+        match self.value {
+            Value::Obj(Object::String { chars }) => {
+                self.check_live();
+                Some(chars.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Panic if the object this refers to may have been freed.
+    fn check_live(&self) {
+        if let Some(root) = &self.root {
+            assert!(
+                root.table.borrow().live,
+                "Handle used after its Vm was dropped."
+            );
+        }
+    }
+}
+
+impl From<f64> for Handle {
+    fn from(n: f64) -> Self {
+        Handle {
+            value: Value::Number(n),
+            root: None,
+        }
+    }
+}
+
+impl From<bool> for Handle {
+    fn from(b: bool) -> Self {
+        Handle {
+            value: Value::Bool(b),
+            root: None,
+        }
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        let root = self.root.as_ref().map(|root| Root {
+            table: Rc::clone(&root.table),
+            key: root.table.borrow_mut().insert(self.value),
+        });
+        Handle {
+            value: self.value,
+            root,
+        }
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        self.table.borrow_mut().values.remove(&self.key);
+    }
+}
+
+/// Equal as Lox's `==` would find them.
+impl PartialEq for Handle {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+/// Shows the value as Lox prints it.
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.check_live();
+        self.value.fmt(f)
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the address of an object is shown, so this is safe to call
+        // after the `Vm` is gone.
+        f.debug_tuple("Handle").field(&self.value).finish()
+    }
+}
+
+/// The values a `Vm` has handed out handles to, which it marks as roots.
+/// Dropping this, with the `Vm`, stops the handles being read.
+pub(crate) struct Handles(Rc<RefCell<Table>>);
+
+impl Handles {
+    pub(crate) fn new() -> Self {
+        Handles(Rc::new(RefCell::new(Table {
+            live: true,
+            ..Table::default()
+        })))
+    }
+
+    /// A handle to `value`, which must be reachable until this returns.
+    pub(crate) fn handle(&self, value: Value) -> Handle {
+        let root = match value {
+            Value::Obj(_) => Some(Root {
+                table: Rc::clone(&self.0),
+                key: self.0.borrow_mut().insert(value),
+            }),
+            _ => None,
+        };
+        Handle { value, root }
+    }
+
+    /// The value `handle` refers to, which stays reachable while the handle
+    /// exists.
+    ///
+    /// # Panics
+    ///
+    /// If `handle` refers to an object belonging to another `Vm`.
+    pub(crate) fn value(&self, handle: &Handle) -> Value {
+        if let Some(root) = &handle.root {
+            assert!(
+                Rc::ptr_eq(&root.table, &self.0),
+                "Handle passed to a Vm that didn't make it."
+            );
+        }
+        handle.value
+    }
+}
+
+impl Trace for Handles {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.0.borrow().values.values() {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl Drop for Handles {
+    fn drop(&mut self) {
+        let mut table = self.0.borrow_mut();
+        table.live = false;
+        table.values.clear();
+    }
+}
@@ -2,54 +2,234 @@
 #![feature(let_chains)]
 #![feature(trace_macros)]
 #![allow(dead_code)]
-#![feature(hash_set_entry)]
 mod chunk;
 mod compiler;
+mod diagnostic;
+mod expect;
+mod handle;
+mod memory;
+mod natives;
 mod object;
+pub mod repl;
 mod scanner;
 mod strings;
 mod value;
 pub mod vm;
 
+pub use chunk::{LoadError, VerifyError, VerifyErrorKind};
+pub use diagnostic::{Diagnostic, Severity};
+pub use handle::Handle;
+pub use natives::NativeContext;
+
+use rustyline::{error::ReadlineError, DefaultEditor};
 use std::{
     fs,
-    io::{self, stderr, stdout, Write},
+    io::{self, stderr, stdout, Cursor, Read, Write},
+    path::{Path, PathBuf},
 };
 
-pub fn repl() -> Result<(), LoxError> {
+/// Read and evaluate lines from stdin, with line editing and history,
+/// until end of input. Errors are reported and the session carries on.
+pub fn run_repl(trace: bool) -> Result<(), LoxError> {
+    let mut vm = vm::Vm::new(stdout(), stderr());
+    vm.set_trace(trace);
+    let mut repl = repl::Repl::new(vm);
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let mut source = String::new();
-        io::stdin().read_line(&mut source)?;
-        vm::interpret(&source, &mut stdout(), &mut stderr())?;
+        match editor.readline(repl.prompt()) {
+            Ok(line) => {
+                editor
+                    .add_history_entry(line.as_str())
+                    .map_err(readline_error)?;
+                // Errors have already been reported.
+                let _ = repl.push_line(&line);
+            }
+            Err(ReadlineError::Interrupted) => repl.cancel(),
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(readline_error(err).into()),
+        }
+    }
+}
+
+fn readline_error(err: ReadlineError) -> io::Error {
+    match err {
+        ReadlineError::Io(err) => err,
+        err => io::Error::other(err),
     }
 }
 
-pub fn run_file(path: &str) -> Result<(), LoxError> {
-    vm::interpret(
-        fs::read_to_string(path)?.as_str(),
-        &mut stdout(),
-        &mut stderr(),
-    )
-    .map_err(|e| e.into())
+/// Run the script at `path`, which may be Lox source or bytecode written by
+/// `compile_file`. With `trace`, each instruction is written to stderr as it
+/// runs.
+pub fn run_file(path: &str, trace: bool) -> Result<(), LoxError> {
+    let bytes = read_input(path)?;
+    let mut vm = vm::Vm::new(stdout(), stderr());
+    vm.set_trace(trace);
+    if bytes.starts_with(chunk::MAGIC) {
+        vm.eval_bytecode(&mut bytes.as_slice())?;
+    } else {
+        vm.eval(&to_source(bytes)?)?;
+    }
+    Ok(())
+}
+
+/// Compile the Lox source at `path` without running it, reporting any
+/// errors to stderr.
+pub fn check_file(path: &str) -> Result<(), LoxError> {
+    let source = to_source(read_input(path)?)?;
+    vm::Vm::new(stdout(), stderr()).compile(&source)?;
+    Ok(())
+}
+
+/// Compile the Lox source at `path` and print it in the textual assembly
+/// dialect read by `assemble_file`.
+pub fn disassemble_file(path: &str) -> Result<(), LoxError> {
+    let source = to_source(read_input(path)?)?;
+    let text = vm::Vm::new(stdout(), stderr()).disassemble(&source)?;
+    print!("{text}");
+    Ok(())
+}
+
+/// Compile the Lox source at `path` and write it as bytecode to `output`.
+pub fn compile_file(path: &str, output: &str) -> Result<(), LoxError> {
+    let source = to_source(read_input(path)?)?;
+    let bytes = vm::Vm::new(stdout(), stderr()).compile(&source)?;
+    write_output(output, &bytes)?;
+    Ok(())
+}
+
+/// Assemble the file at `path`, written in the textual assembly dialect,
+/// and write it as bytecode to `output`.
+pub fn assemble_file(path: &str, output: &str) -> Result<(), LoxError> {
+    let text = to_source(read_input(path)?)?;
+    let bytes = vm::Vm::new(stdout(), stderr()).assemble(&text)?;
+    write_output(output, &bytes)?;
+    Ok(())
+}
+
+/// Compile the Lox source at `path` and print it as JSON, for tools that
+/// inspect the compiler's output.
+pub fn dump_json_file(path: &str) -> Result<(), LoxError> {
+    let source = to_source(read_input(path)?)?;
+    let json = vm::Vm::new(stdout(), stderr()).dump_json(&source)?;
+    println!("{json}");
+    Ok(())
+}
+
+/// Run every `.lox` file under `path`, or just `path` if it is a file, and
+/// check what each does against the expectations in its comments, as
+/// described in `expect`. Failures are printed with a summary at the end.
+pub fn test_path(path: &str) -> Result<(), LoxError> {
+    let mut files = vec![];
+    find_scripts(Path::new(path), &mut files)?;
+    let mut failed = 0;
+    for file in &files {
+        let bytes = fs::read(file).map_err(|err| with_path(&file.to_string_lossy(), err))?;
+        let source = to_source(bytes)?;
+        let mut vm = vm::Vm::new(Cursor::new(Vec::new()), io::sink());
+        let result = vm.eval(&source);
+        let (out_stream, _) = vm.into_streams();
+        let output = String::from_utf8_lossy(out_stream.get_ref());
+        let failures = expect::Expectations::parse(&source).check(&source, &output, &result);
+        if !failures.is_empty() {
+            failed += 1;
+            println!("FAIL {}", file.display());
+            for failure in failures {
+                println!("    {failure}");
+            }
+        }
+    }
+    println!("{} passed, {failed} failed.", files.len() - failed);
+    if failed > 0 {
+        return Err(LoxError::TestsFailed(failed));
+    }
+    Ok(())
 }
 
+/// Add the `.lox` files under `path` to `files`, in order of their paths.
+fn find_scripts(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let with_path = |err| with_path(&path.to_string_lossy(), err);
+    if !fs::metadata(path).map_err(with_path)?.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect())
+        .map_err(with_path)?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "lox") {
+            find_scripts(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// The contents of the file at `path`, or of stdin if `path` is `-`.
+fn read_input(path: &str) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(path).map_err(|err| with_path(path, err))
+    }
+}
+
+/// `err` with `path` prepended to its message, keeping its kind.
+fn with_path(path: &str, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{path}: {err}"))
+}
+
+fn to_source(bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Write `bytes` to the file at `path`, or to stdout if `path` is `-`.
+fn write_output(path: &str, bytes: &[u8]) -> io::Result<()> {
+    if path == "-" {
+        io::stdout().write_all(bytes)
+    } else {
+        fs::write(path, bytes).map_err(|err| with_path(path, err))
+    }
+}
+
+/// Why a command stopped. Compile, runtime and load errors, and failed
+/// tests, have already been reported by the time one of these is returned.
+#[derive(Debug)]
 pub enum LoxError {
     CompileError,
-    RuntimeError(String),
-    ReadError,
+    RuntimeError,
+    LoadError,
+    ReadError(io::Error),
+    /// How many test scripts failed.
+    TestsFailed(usize),
 }
+
+impl LoxError {
+    /// The process exit status for this error, following `sysexits.h` as
+    /// clox does.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::CompileError | LoxError::LoadError => 65,
+            LoxError::RuntimeError => 70,
+            LoxError::ReadError(_) => 74,
+            LoxError::TestsFailed(_) => 1,
+        }
+    }
+}
+
 impl From<vm::InterpretError> for LoxError {
     fn from(value: vm::InterpretError) -> Self {
         match value {
-            vm::InterpretError::CompileError => LoxError::CompileError,
-            vm::InterpretError::RuntimeError(msg) => LoxError::RuntimeError(msg),
+            vm::InterpretError::CompileError(_) => LoxError::CompileError,
+            vm::InterpretError::RuntimeError { .. } => LoxError::RuntimeError,
+            vm::InterpretError::LoadError(_) => LoxError::LoadError,
         }
     }
 }
 impl From<std::io::Error> for LoxError {
-    fn from(_value: std::io::Error) -> Self {
-        LoxError::ReadError
+    fn from(value: std::io::Error) -> Self {
+        LoxError::ReadError(value)
     }
 }
@@ -1,27 +1,122 @@
-use lox::{repl, run_file, LoxError};
+use lox::{
+    assemble_file, check_file, compile_file, disassemble_file, dump_json_file, run_file, run_repl,
+    test_path, LoxError,
+};
 
+use std::path::Path;
 use std::process;
 
+const USAGE: &str = "\
+Usage: lox [run] [--trace] <file>
+       lox [repl] [--trace]
+       lox check <file>
+       lox disasm <file>
+       lox compile <file> [-o <output>]
+       lox asm <file> [-o <output>]
+       lox json <file>
+       lox test <path>
+
+<file> may be - to read from stdin, and <output> - to write to stdout.
+Output defaults to <file> with a .loxc extension. test runs <path>, or
+every .lox file under it, checking // expect: comments.";
+
+const SUBCOMMANDS: [&str; 8] = [
+    "run", "repl", "check", "disasm", "compile", "asm", "json", "test",
+];
+
+enum Command {
+    Run { path: String, trace: bool },
+    Repl { trace: bool },
+    Check { path: String },
+    Disasm { path: String },
+    Compile { path: String, output: String },
+    Asm { path: String, output: String },
+    Json { path: String },
+    Test { path: String },
+    Help,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (name, rest) = match args.split_first() {
+        Some((name, rest)) if SUBCOMMANDS.contains(&name.as_str()) => (name.as_str(), rest),
+        Some(_) => ("run", args),
+        None => ("repl", args),
+    };
+
+    let mut path = None;
+    let mut output = None;
+    let mut trace = false;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--trace" if name == "run" || name == "repl" => trace = true,
+            "-o" if name == "compile" || name == "asm" => match rest.next() {
+                Some(arg) => output = Some(arg.clone()),
+                None => return Err("-o needs an output path".to_string()),
+            },
+            option if option.starts_with('-') && option != "-" => {
+                return Err(format!("unknown option '{option}' for {name}"));
+            }
+            _ if path.is_some() || name == "repl" => {
+                return Err(format!("unexpected argument '{arg}'"));
+            }
+            _ => path = Some(arg.clone()),
+        }
+    }
+
+    if name == "repl" {
+        return Ok(Command::Repl { trace });
+    }
+    // With no arguments at all we start the REPL, so `run` needs a path.
+    let path = path.ok_or_else(|| format!("{name} needs a file"))?;
+    let output = match output {
+        Some(output) => output,
+        None if path == "-" => String::from("-"),
+        None => Path::new(&path)
+            .with_extension("loxc")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    Ok(match name {
+        "run" => Command::Run { path, trace },
+        "check" => Command::Check { path },
+        "disasm" => Command::Disasm { path },
+        "compile" => Command::Compile { path, output },
+        "asm" => Command::Asm { path, output },
+        "json" => Command::Json { path },
+        _ => Command::Test { path },
+    })
+}
+
 fn main() {
-    let result = match ::std::env::args().len() {
-        1 => repl(),
-        2 => run_file(std::env::args().collect::<Vec<String>>()[1].as_str()),
-        _ => {
-            eprintln!("Usage: clox [path]");
+    let args: Vec<String> = ::std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("lox: {message}\n\n{USAGE}");
             process::exit(64);
         }
     };
-    match result {
-        Ok(()) => {}
-        Err(err) => {
-            println!(
-                "{}",
-                match err {
-                    LoxError::CompileError => "Compile error!".to_string(),
-                    LoxError::RuntimeError(msg) => format!("Runtime error!: {msg}"),
-                    LoxError::ReadError => "Read error!".to_string(),
-                }
-            )
+    let result = match command {
+        Command::Run { path, trace } => run_file(&path, trace),
+        Command::Repl { trace } => run_repl(trace),
+        Command::Check { path } => check_file(&path),
+        Command::Disasm { path } => disassemble_file(&path),
+        Command::Compile { path, output } => compile_file(&path, &output),
+        Command::Asm { path, output } => assemble_file(&path, &output),
+        Command::Json { path } => dump_json_file(&path),
+        Command::Test { path } => test_path(&path),
+        Command::Help => {
+            println!("{USAGE}");
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        // Other errors have already been reported.
+        if let LoxError::ReadError(err) = &err {
+            eprintln!("lox: {err}");
         }
+        process::exit(err.exit_code());
     }
 }
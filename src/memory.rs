@@ -0,0 +1,315 @@
+use std::{
+    cell::Cell,
+    cmp,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    ops::Deref,
+    ptr::NonNull,
+};
+
+use rstest::rstest;
+
+use crate::strings::Strings;
+
+/// Bytes that may be allocated before the first collection.
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+pub const DEFAULT_GROWTH_FACTOR: usize = 2;
+
+/// Implemented by everything stored on the `Heap`, and by anything else that
+/// can hold references to heap objects. `trace` marks every object directly
+/// reachable from `self`.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Bytes owned by `self` outside its own struct, such as a `String`'s or
+    /// `Vec`'s buffer, which count towards the next collection.
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// Bytes in the buffer behind `vec`.
+pub fn vec_size<T>(vec: &Vec<T>) -> usize {
+    vec.capacity() * mem::size_of::<T>()
+}
+
+/// Roughly the bytes in the table behind `map`.
+pub fn map_size<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * mem::size_of::<(K, V)>()
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    /// The bytes counted for this object in `Heap::bytes_allocated`.
+    size: Cell<usize>,
+    value: T,
+}
+
+/// A handle to an object owned by a `Heap`.
+///
+/// Handles are plain pointers: they are only valid while the object is
+/// reachable from the roots given to `Heap::collect`, so anything holding a
+/// `Gc` across an allocation must make sure it is traced.
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T> Gc<T> {
+    pub fn ptr_eq(a: Gc<T>, b: Gc<T>) -> bool {
+        a.ptr == b.ptr
+    }
+
+    pub fn is_marked(&self) -> bool {
+        unsafe { self.ptr.as_ref() }.marked.get()
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the heap only frees objects that were unreachable at the
+        // last collection, and no live handle can point at one of those.
+        &unsafe { self.ptr.as_ref() }.value
+    }
+}
+
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gc({:p})", self.ptr)
+    }
+}
+
+pub struct Tracer {
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+}
+
+impl Tracer {
+    pub fn mark<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        let gc_box = unsafe { object.ptr.as_ref() };
+        if gc_box.marked.replace(true) {
+            return;
+        }
+        self.gray.push(object.ptr);
+    }
+}
+
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    growth_factor: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: vec![],
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            growth_factor: DEFAULT_GROWTH_FACTOR,
+        }
+    }
+
+    /// After each collection the next one is scheduled for when the heap has
+    /// grown to `growth_factor` times the size of what survived.
+    pub fn set_growth_factor(&mut self, growth_factor: usize) {
+        self.growth_factor = growth_factor;
+    }
+
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let size = mem::size_of::<GcBox<T>>() + value.heap_size();
+        let gc_box = Box::new(GcBox {
+            marked: Cell::new(false),
+            size: Cell::new(size),
+            value,
+        });
+        self.bytes_allocated += size;
+        let ptr = NonNull::from(Box::leak(gc_box));
+        self.objects.push(ptr);
+        Gc { ptr }
+    }
+
+    /// Count `object` again after it has grown or shrunk in place, as a list
+    /// does when items are pushed to it.
+    pub fn resize<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        let gc_box = unsafe { object.ptr.as_ref() };
+        let size = mem::size_of::<GcBox<T>>() + gc_box.value.heap_size();
+        self.bytes_allocated = self.bytes_allocated - gc_box.size.replace(size) + size;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc-stress") || self.bytes_allocated > self.next_gc
+    }
+
+    /// Free every object that is not reachable from the roots marked by
+    /// `mark_roots`, dropping dead strings from `strings` first.
+    pub fn collect<F: FnOnce(&mut Tracer)>(&mut self, strings: &mut Strings, mark_roots: F) {
+        let mut tracer = Tracer { gray: vec![] };
+        mark_roots(&mut tracer);
+        while let Some(ptr) = tracer.gray.pop() {
+            unsafe { ptr.as_ref() }.value.trace(&mut tracer);
+        }
+
+        strings.remove_unmarked();
+        self.sweep();
+        self.next_gc = cmp::max(self.bytes_allocated * self.growth_factor, INITIAL_NEXT_GC);
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|ptr| {
+            let gc_box = unsafe { ptr.as_ref() };
+            if gc_box.marked.replace(false) {
+                true
+            } else {
+                freed += gc_box.size.get();
+                drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+                false
+            }
+        });
+        self.bytes_allocated -= freed;
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for ptr in self.objects.drain(..) {
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        }
+    }
+}
+
+impl Trace for String {
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(test)]
+struct Node(Cell<Option<Gc<Node>>>);
+
+#[cfg(test)]
+impl Trace for Node {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(next) = self.0.get() {
+            tracer.mark(next);
+        }
+    }
+}
+
+#[rstest]
+fn collect_frees_unreachable_objects() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let root = heap.alloc(Node(Cell::new(None)));
+    let child = heap.alloc(Node(Cell::new(None)));
+    root.0.set(Some(child));
+    heap.alloc(Node(Cell::new(None)));
+    assert_eq!(heap.len(), 3);
+
+    heap.collect(&mut strings, |tracer| tracer.mark(root));
+    assert_eq!(heap.len(), 2);
+
+    heap.collect(&mut strings, |_| {});
+    assert_eq!(heap.len(), 0);
+}
+
+#[rstest]
+fn collect_frees_cycles() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let a = heap.alloc(Node(Cell::new(None)));
+    let b = heap.alloc(Node(Cell::new(Some(a))));
+    a.0.set(Some(b));
+
+    heap.collect(&mut strings, |tracer| tracer.mark(a));
+    assert_eq!(heap.len(), 2);
+
+    heap.collect(&mut strings, |_| {});
+    assert_eq!(heap.len(), 0);
+}
+
+#[rstest]
+fn collect_removes_dead_strings() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let kept = strings.new_string(&mut heap, "kept".to_string());
+    strings.new_string(&mut heap, "dropped".to_string());
+
+    heap.collect(&mut strings, |tracer| tracer.mark(kept));
+    assert_eq!(heap.len(), 1);
+    assert_eq!(strings.len(), 1);
+}
+
+#[rstest]
+fn buffers_count_towards_collection() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    strings.new_string(&mut heap, "x".repeat(INITIAL_NEXT_GC));
+    assert!(heap.should_collect());
+
+    heap.collect(&mut strings, |_| {});
+    assert_eq!(heap.bytes_allocated, 0);
+}
+
+#[cfg(test)]
+struct Buffer(std::cell::RefCell<Vec<u8>>);
+
+#[cfg(test)]
+impl Trace for Buffer {
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn heap_size(&self) -> usize {
+        vec_size(&self.0.borrow())
+    }
+}
+
+#[rstest]
+fn resize_counts_growth() {
+    let mut heap = Heap::new();
+    let mut strings = Strings::new();
+    let buffer = heap.alloc(Buffer(Default::default()));
+    buffer.0.borrow_mut().resize(INITIAL_NEXT_GC, 0);
+    heap.resize(buffer);
+    assert!(heap.bytes_allocated > INITIAL_NEXT_GC);
+
+    heap.collect(&mut strings, |_| {});
+    assert_eq!(heap.bytes_allocated, 0);
+}
@@ -0,0 +1,276 @@
+use std::io::{self, BufRead};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::handle::{Handle, Handles};
+use crate::memory::{Gc, Heap, Trace, Tracer};
+use crate::object::{self, Iter, List, Map, Object};
+use crate::strings::Strings;
+use crate::value::Value;
+
+/// What a native function may touch while it runs.
+pub struct NativeContext<'a> {
+    pub(crate) heap: &'a mut Heap,
+    pub(crate) strings: &'a mut Strings,
+    /// Marks everything the VM holds, including the arguments, which stay on
+    /// its stack until the native returns.
+    pub(crate) mark_roots: &'a dyn Fn(&mut Tracer),
+    pub(crate) handles: &'a Handles,
+    /// Every object made during the call. These are treated as roots until
+    /// the native returns.
+    pub(crate) objects: Vec<Object>,
+}
+
+impl NativeContext<'_> {
+    /// Make a Lox string, e.g. to return from the native.
+    pub fn new_string(&mut self, string: String) -> Handle {
+        let value = self.string(string);
+        self.handles.handle(value)
+    }
+
+    /// Make a Lox list of `items`.
+    ///
+    /// # Panics
+    ///
+    /// If an item refers to an object belonging to another `Vm`.
+    pub fn new_list(&mut self, items: &[Handle]) -> Handle {
+        let items = items.iter().map(|item| self.handles.value(item)).collect();
+        let value = self.list(items);
+        self.handles.handle(value)
+    }
+
+    pub(crate) fn string(&mut self, string: String) -> Value {
+        let chars = self.strings.new_string(self.heap, string);
+        self.keep(Object::String { chars })
+    }
+
+    pub(crate) fn list(&mut self, items: Vec<Value>) -> Value {
+        let list = self.heap.alloc(List::new(items));
+        self.keep(Object::List(list))
+    }
+
+    pub(crate) fn iter(&mut self, iter: Iter) -> Value {
+        let iter = self.heap.alloc(iter);
+        self.keep(Object::Iter(iter))
+    }
+
+    /// Root `object`, which has just been allocated, until the native
+    /// returns, and collect garbage if it is due.
+    fn keep(&mut self, object: Object) -> Value {
+        self.objects.push(object);
+        if self.heap.should_collect() {
+            let objects = &self.objects;
+            let mark_roots = self.mark_roots;
+            self.heap.collect(self.strings, |tracer| {
+                mark_roots(tracer);
+                for object in objects {
+                    object.trace(tracer);
+                }
+            });
+        }
+        Value::Obj(object)
+    }
+}
+
+/// Seconds elapsed since the clock was created.
+pub fn clock() -> impl Fn(&mut NativeContext, &[Value]) -> Result<Value, String> {
+    let start = Instant::now();
+    move |_, _| Ok(Value::Number(start.elapsed().as_secs_f64()))
+}
+
+pub fn sleep(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Number(seconds) if seconds >= 0.0 => {
+            thread::sleep(Duration::from_secs_f64(seconds));
+            Ok(Value::Nil)
+        }
+        _ => Err("sleep() expects a non-negative number of seconds.".to_string()),
+    }
+}
+
+/// Read a line from stdin without its line ending, or nil at end of input.
+pub fn input(context: &mut NativeContext, _: &[Value]) -> Result<Value, String> {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+            line.truncate(len);
+            Ok(context.string(line))
+        }
+        Err(err) => Err(format!("input() failed: {err}.")),
+    }
+}
+
+pub fn exit(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Number(code) if code.fract() == 0.0 => process::exit(code as i32),
+        _ => Err("exit() expects an integer exit code.".to_string()),
+    }
+}
+
+/// The numbers from `start` up to, but not including, `end`, to loop over
+/// with `for-in`.
+pub fn range(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match (args[0], args[1]) {
+        (Value::Number(start), Value::Number(end)) => Ok(context.iter(Iter::range(start, end))),
+        _ => Err("range() expects two numbers.".to_string()),
+    }
+}
+
+/// A native method: its name, its arity not counting the receiver, and its
+/// implementation, which is passed the receiver before the arguments.
+pub type Method = (
+    &'static str,
+    usize,
+    fn(&mut NativeContext, &[Value]) -> Result<Value, String>,
+);
+
+pub const LIST_METHODS: [Method; 6] = [
+    ("push", 1, list_push),
+    ("pop", 0, list_pop),
+    ("len", 0, list_len),
+    ("insert", 2, list_insert),
+    ("remove", 1, list_remove),
+    ("slice", 2, list_slice),
+];
+
+pub const STRING_METHODS: [Method; 2] = [("len", 0, string_len), ("slice", 2, string_slice)];
+
+pub const MAP_METHODS: [Method; 5] = [
+    ("has", 1, map_has),
+    ("keys", 0, map_keys),
+    ("values", 0, map_values),
+    ("delete", 1, map_delete),
+    ("len", 0, map_len),
+];
+
+/// The list a list method was called on.
+fn list_receiver(args: &[Value]) -> Gc<List> {
+    match args[0] {
+        Value::Obj(Object::List(list)) => list,
+        _ => unreachable!("List method called on a non-list!"),
+    }
+}
+
+pub fn list_push(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let list = list_receiver(args);
+    list.items.borrow_mut().push(args[1]);
+    context.heap.resize(list);
+    Ok(Value::Nil)
+}
+
+pub fn list_pop(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match list_receiver(args).items.borrow_mut().pop() {
+        Some(item) => Ok(item),
+        None => Err("Can't pop from an empty list.".to_string()),
+    }
+}
+
+pub fn list_len(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(list_receiver(args).items.borrow().len() as f64))
+}
+
+/// Insert an item before `index`, which may also be the length of the list
+/// to add it at the end.
+pub fn list_insert(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let list = list_receiver(args);
+    let len = list.items.borrow().len();
+    match List::offset(args[1], len)? {
+        offset if offset >= 0 && offset <= len as i64 => {
+            list.items.borrow_mut().insert(offset as usize, args[2]);
+            context.heap.resize(list);
+            Ok(Value::Nil)
+        }
+        _ => Err("List index out of range.".to_string()),
+    }
+}
+
+pub fn list_remove(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let list = list_receiver(args);
+    let index = list.index(args[1])?;
+    let item = list.items.borrow_mut().remove(index);
+    Ok(item)
+}
+
+/// A new list of the items from `start` up to but not including `end`.
+/// Either may be nil for the start or end of the list, and out of range
+/// bounds are clamped to it.
+pub fn list_slice(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let list = list_receiver(args);
+    let len = list.items.borrow().len();
+    let start = slice_bound(args[1], 0, len, List::offset)?;
+    let end = slice_bound(args[2], len, len, List::offset)?;
+    let items = list.items.borrow()[start..end.max(start)].to_vec();
+    Ok(context.list(items))
+}
+
+/// A bound of a slice of `len` items, `default` if it is nil, clamped to
+/// the items if it is out of range.
+fn slice_bound(
+    bound: Value,
+    default: usize,
+    len: usize,
+    offset: fn(Value, usize) -> Result<i64, String>,
+) -> Result<usize, String> {
+    match bound {
+        Value::Nil => Ok(default),
+        bound => Ok(offset(bound, len)?.clamp(0, len as i64) as usize),
+    }
+}
+
+/// The string a string method was called on.
+fn string_receiver(args: &[Value]) -> Gc<String> {
+    match args[0] {
+        Value::Obj(Object::String { chars }) => chars,
+        _ => unreachable!("String method called on a non-string!"),
+    }
+}
+
+/// The number of characters in the string, which may be fewer than the
+/// number of bytes.
+pub fn string_len(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(string_receiver(args).chars().count() as f64))
+}
+
+/// The characters from `start` up to but not including `end`, with bounds
+/// as for a list's `slice`.
+pub fn string_slice(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let string = string_receiver(args);
+    let len = string.chars().count();
+    let start = slice_bound(args[1], 0, len, object::string_offset)?;
+    let end = slice_bound(args[2], len, len, object::string_offset)?;
+    let slice = string.chars().skip(start).take(end.saturating_sub(start)).collect();
+    Ok(context.string(slice))
+}
+
+/// The map a map method was called on.
+fn map_receiver(args: &[Value]) -> Gc<Map> {
+    match args[0] {
+        Value::Obj(Object::Map(map)) => map,
+        _ => unreachable!("Map method called on a non-map!"),
+    }
+}
+
+pub fn map_has(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Bool(map_receiver(args).get(args[1])?.is_some()))
+}
+
+/// The keys of the map, as a list in the order they were added.
+pub fn map_keys(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(context.list(map_receiver(args).keys()))
+}
+
+pub fn map_values(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(context.list(map_receiver(args).values()))
+}
+
+/// Remove a key, returning whether it was there.
+pub fn map_delete(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Bool(map_receiver(args).delete(args[1])?))
+}
+
+pub fn map_len(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(map_receiver(args).len() as f64))
+}
@@ -1,11 +1,28 @@
-use std::{fmt, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+};
 
 use crate::chunk::Chunk;
+use crate::memory::{map_size, vec_size, Gc, Trace, Tracer};
+use crate::natives::NativeContext;
+use crate::value::Value;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Object {
-    String { chars: Rc<String> },
-    Function(Function),
+    String { chars: Gc<String> },
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Upvalue(Gc<RefCell<Upvalue>>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
+    BoundNative(Gc<BoundNative>),
+    List(Gc<List>),
+    Map(Gc<Map>),
+    Iter(Gc<Iter>),
 }
 
 impl fmt::Display for Object {
@@ -13,13 +30,43 @@ impl fmt::Display for Object {
         match self {
             Object::String { chars } => write!(f, "\"{chars}\""),
             Object::Function(function) => function.fmt(f),
+            Object::Closure(closure) => closure.function.fmt(f),
+            Object::Upvalue(_) => write!(f, "upvalue"),
+            Object::Class(class) => write!(f, "{}", class.name),
+            Object::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Object::BoundMethod(bound) => bound.method.function.fmt(f),
+            Object::Native(native) => write!(f, "native fn <{}>", native.name),
+            Object::BoundNative(bound) => write!(f, "native fn <{}>", bound.native.name),
+            Object::List(list) => list.fmt(f),
+            Object::Map(map) => map.fmt(f),
+            Object::Iter(_) => write!(f, "iterator"),
+        }
+    }
+}
+
+impl Trace for Object {
+    fn trace(&self, tracer: &mut Tracer) {
+        match *self {
+            Object::String { chars } => tracer.mark(chars),
+            Object::Function(function) => tracer.mark(function),
+            Object::Closure(closure) => tracer.mark(closure),
+            Object::Upvalue(upvalue) => tracer.mark(upvalue),
+            Object::Class(class) => tracer.mark(class),
+            Object::Instance(instance) => tracer.mark(instance),
+            Object::BoundMethod(bound) => tracer.mark(bound),
+            Object::Native(native) => tracer.mark(native),
+            Object::BoundNative(bound) => tracer.mark(bound),
+            Object::List(list) => tracer.mark(list),
+            Object::Map(map) => tracer.mark(map),
+            Object::Iter(iter) => tracer.mark(iter),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Function {
-    arity: usize,
+    pub arity: usize,
+    pub upvalue_count: usize,
     pub chunk: Chunk,
     pub name: Option<String>,
 }
@@ -28,6 +75,7 @@ impl Function {
     pub fn new(name: Option<String>) -> Self {
         Function {
             arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::new(),
             name,
         }
@@ -38,7 +86,469 @@ impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.name {
             Some(name) => write!(f, "fn <{name}>"),
-            None => write!(f, " <script>")
+            None => write!(f, " <script>"),
+        }
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        for constant in &self.chunk.constants {
+            constant.trace(tracer);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        let chunk = &self.chunk;
+        vec_size(&chunk.code)
+            + vec_size(&chunk.constants)
+            + vec_size(&chunk.line_nos)
+            + vec_size(&chunk.columns)
+            + self.name.as_ref().map_or(0, String::capacity)
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Gc<Function>, upvalues: Vec<Gc<RefCell<Upvalue>>>) -> Self {
+        Closure { function, upvalues }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.function);
+        for upvalue in &self.upvalues {
+            tracer.mark(*upvalue);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        vec_size(&self.upvalues)
+    }
+}
+
+/// A variable captured by a closure. While the variable is still live on the
+/// VM stack the upvalue points at its slot; once the slot is popped the value
+/// is moved into the upvalue itself.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Upvalue::Closed(value) = &*self.borrow() {
+            value.trace(tracer);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Gc<String>,
+    pub methods: RefCell<HashMap<Gc<String>, Gc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: Gc<String>) -> Self {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+        for (name, method) in self.methods.borrow().iter() {
+            tracer.mark(*name);
+            tracer.mark(*method);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        map_size(&self.methods.borrow())
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Gc<Class>,
+    pub fields: RefCell<HashMap<Gc<String>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Self {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+        for (name, value) in self.fields.borrow().iter() {
+            tracer.mark(*name);
+            value.trace(tracer);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        map_size(&self.fields.borrow())
+    }
+}
+
+/// A method closure paired with the instance it was accessed on, so `this`
+/// still refers to that instance when the method is called later.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Gc<Closure>) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        tracer.mark(self.method);
+    }
+}
+
+pub type NativeFn = Box<dyn Fn(&mut NativeContext, &[Value]) -> Result<Value, String>>;
+
+/// A function implemented in Rust. Returning `Err` raises a runtime error with
+/// that message.
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: String, arity: usize, function: NativeFn) -> Self {
+        Native {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({}/{})", self.name, self.arity)
+    }
+}
+
+impl Trace for Native {
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn heap_size(&self) -> usize {
+        self.name.capacity()
+    }
+}
+
+/// A native method paired with the value it was accessed on, which is passed
+/// to the native as its first argument.
+#[derive(Debug)]
+pub struct BoundNative {
+    pub receiver: Value,
+    pub native: Gc<Native>,
+}
+
+impl BoundNative {
+    pub fn new(receiver: Value, native: Gc<Native>) -> Self {
+        BoundNative { receiver, native }
+    }
+}
+
+impl Trace for BoundNative {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        tracer.mark(self.native);
+    }
+}
+
+#[derive(Debug)]
+pub struct List {
+    pub items: RefCell<Vec<Value>>,
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) -> Self {
+        List {
+            items: RefCell::new(items),
+        }
+    }
+
+    /// The position of the item `index` refers to, counting back from the
+    /// end if it is negative.
+    pub fn index(&self, index: Value) -> Result<usize, String> {
+        let len = self.items.borrow().len();
+        match List::offset(index, len)? {
+            offset if offset >= 0 && offset < len as i64 => Ok(offset as usize),
+            _ => Err("List index out of range.".to_string()),
+        }
+    }
+
+    /// `index` as an offset into a list of `len` items, counting back from
+    /// the end if it is negative. The offset may be out of range.
+    pub fn offset(index: Value, len: usize) -> Result<i64, String> {
+        offset(index, len).ok_or_else(|| "List index must be an integer.".to_string())
+    }
+}
+
+/// `index` as an offset into `len` items, counting back from the end if it
+/// is negative, or `None` if it isn't an integer.
+fn offset(index: Value, len: usize) -> Option<i64> {
+    match index {
+        Value::Number(index) if index.fract() == 0.0 => {
+            let index = index as i64;
+            Some(if index < 0 { index + len as i64 } else { index })
+        }
+        _ => None,
+    }
+}
+
+/// `index` as an offset into a string of `len` characters, as for
+/// `List::offset`. Strings are indexed by Unicode scalar value, not byte.
+pub fn string_offset(index: Value, len: usize) -> Result<i64, String> {
+    offset(index, len).ok_or_else(|| "String index must be an integer.".to_string())
+}
+
+/// The character at `index` in `string`, counting back from the end if it
+/// is negative.
+pub fn char_at(string: &str, index: Value) -> Result<char, String> {
+    let len = string.chars().count();
+    match string_offset(index, len)? {
+        offset if offset >= 0 && offset < len as i64 => {
+            Ok(string.chars().nth(offset as usize).unwrap())
+        }
+        _ => Err("String index out of range.".to_string()),
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A list that contains itself is already borrowed further up.
+        let items = match self.items.try_borrow_mut() {
+            Ok(items) => items,
+            Err(_) => return write!(f, "[...]"),
+        };
+        write!(f, "[")?;
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{item}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl Trace for List {
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in self.items.borrow().iter() {
+            item.trace(tracer);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        vec_size(&self.items.borrow())
+    }
+}
+
+/// A value that can be used as a map key. Strings are interned, so equal
+/// strings are the same object and hash by pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MapKey {
+    Nil,
+    Bool(bool),
+    /// The number's bits, with -0 folded into 0 since they compare equal.
+    Number(u64),
+    String(Gc<String>),
+}
+
+const UNHASHABLE_KEY: &str = "Map keys must be strings, numbers, booleans or nil.";
+
+impl MapKey {
+    fn new(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Bool(b) => Ok(MapKey::Bool(b)),
+            // NaN is never equal to itself, so no lookup could find it.
+            Value::Number(n) if n.is_nan() => Err(UNHASHABLE_KEY.to_string()),
+            // Adding 0 turns -0 into 0 and leaves everything else alone.
+            Value::Number(n) => Ok(MapKey::Number((n + 0.0).to_bits())),
+            Value::Obj(Object::String { chars }) => Ok(MapKey::String(chars)),
+            Value::Obj(_) => Err(UNHASHABLE_KEY.to_string()),
+        }
+    }
+}
+
+/// A map from keys to values that remembers the order keys were first
+/// inserted in.
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: RefCell<Vec<(Value, Value)>>,
+    /// The position of each key in `entries`.
+    index: RefCell<HashMap<MapKey, usize>>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map::default()
+    }
+
+    pub fn get(&self, key: Value) -> Result<Option<Value>, String> {
+        let key = MapKey::new(key)?;
+        let position = self.index.borrow().get(&key).copied();
+        Ok(position.map(|position| self.entries.borrow()[position].1))
+    }
+
+    pub fn set(&self, key: Value, value: Value) -> Result<(), String> {
+        let map_key = MapKey::new(key)?;
+        let mut entries = self.entries.borrow_mut();
+        let mut index = self.index.borrow_mut();
+        match index.get(&map_key) {
+            Some(&position) => entries[position].1 = value,
+            None => {
+                index.insert(map_key, entries.len());
+                entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `key`, returning whether it was there.
+    pub fn delete(&self, key: Value) -> Result<bool, String> {
+        let key = MapKey::new(key)?;
+        let mut index = self.index.borrow_mut();
+        let position = match index.remove(&key) {
+            Some(position) => position,
+            None => return Ok(false),
+        };
+        self.entries.borrow_mut().remove(position);
+        for later in index.values_mut() {
+            if *later > position {
+                *later -= 1;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn keys(&self) -> Vec<Value> {
+        self.entries.borrow().iter().map(|(key, _)| *key).collect()
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        self.entries.borrow().iter().map(|(_, value)| *value).collect()
+    }
+
+    /// The key inserted `position`th among those still in the map.
+    pub fn key_at(&self, position: usize) -> Option<Value> {
+        self.entries.borrow().get(position).map(|(key, _)| *key)
+    }
+}
+
+impl fmt::Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A map that contains itself is already borrowed further up.
+        let entries = match self.entries.try_borrow_mut() {
+            Ok(entries) => entries,
+            Err(_) => return write!(f, "{{...}}"),
+        };
+        write!(f, "{{")?;
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{key}: {value}")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl Trace for Map {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (key, value) in self.entries.borrow().iter() {
+            key.trace(tracer);
+            value.trace(tracer);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        vec_size(&self.entries.borrow()) + map_size(&self.index.borrow())
+    }
+}
+
+/// Where a `for-in` loop over a built-in value has got to. Lists and maps
+/// are read as the loop reaches each item, so changes made by the loop body
+/// are seen by later iterations.
+#[derive(Debug)]
+pub enum Iter {
+    List { list: Gc<List>, next: Cell<usize> },
+    /// The keys of the map, in insertion order.
+    Map { map: Gc<Map>, next: Cell<usize> },
+    /// The characters of the string; `next` is a byte offset.
+    String { string: Gc<String>, next: Cell<usize> },
+    /// The numbers from `next` up to, but not including, `end`.
+    Range { next: Cell<f64>, end: f64 },
+}
+
+impl Iter {
+    pub fn new(iterable: Value) -> Option<Self> {
+        match iterable {
+            Value::Obj(Object::List(list)) => Some(Iter::List {
+                list,
+                next: Cell::new(0),
+            }),
+            Value::Obj(Object::Map(map)) => Some(Iter::Map {
+                map,
+                next: Cell::new(0),
+            }),
+            Value::Obj(Object::String { chars }) => Some(Iter::String {
+                string: chars,
+                next: Cell::new(0),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn range(start: f64, end: f64) -> Self {
+        Iter::Range {
+            next: Cell::new(start),
+            end,
+        }
+    }
+}
+
+impl Trace for Iter {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Iter::List { list, .. } => tracer.mark(*list),
+            Iter::Map { map, .. } => tracer.mark(*map),
+            Iter::String { string, .. } => tracer.mark(*string),
+            Iter::Range { .. } => {}
         }
     }
 }
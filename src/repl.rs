@@ -0,0 +1,146 @@
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::time::Instant;
+
+use crate::scanner;
+use crate::vm::{InterpretError, Vm};
+
+/// An interactive session: a VM whose globals persist from one input to the
+/// next, and the lines read so far of an input that isn't complete yet.
+///
+/// A line starting with `:` is a command to the REPL itself rather than
+/// Lox; `:help` lists them.
+pub struct Repl<O: Write, E: Write> {
+    vm: Vm<O, E>,
+    buffer: String,
+    /// Whether to write how long each input took to the error stream.
+    time: bool,
+}
+
+const HELP: &str = "\
+:globals          list every global and its value
+:dis <code>       show the bytecode for <code>
+:load <file>      run <file> in this session
+:reset            forget every global
+:time             toggle timing of each input
+:trace on|off     toggle tracing of each instruction
+:help             show this list";
+
+impl<O: Write, E: Write> Repl<O, E> {
+    pub fn new(vm: Vm<O, E>) -> Self {
+        Repl {
+            vm,
+            buffer: String::new(),
+            time: false,
+        }
+    }
+
+    /// The prompt to show before reading the next line.
+    pub fn prompt(&self) -> &'static str {
+        if self.buffer.is_empty() {
+            "> "
+        } else {
+            "... "
+        }
+    }
+
+    /// Add a line to the input. While the input has an unclosed string,
+    /// brace or paren this returns `None` and waits for more; otherwise the
+    /// input is evaluated, printing the value of any bare expression, and
+    /// the result returned. A blank line evaluates whatever has been typed,
+    /// so a stray `{` can't keep the prompt waiting forever.
+    pub fn push_line(&mut self, line: &str) -> Option<Result<(), InterpretError>> {
+        if self.buffer.is_empty()
+            && let Some(command) = line.trim().strip_prefix(':')
+        {
+            return Some(self.run_command(command));
+        }
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if !line.trim().is_empty() && scanner::is_incomplete(&self.buffer) {
+            return None;
+        }
+        let source = mem::take(&mut self.buffer);
+        Some(self.timed(|vm| vm.eval_repl(&source)))
+    }
+
+    /// Run a `:` command, given without its colon. Mistakes in the command
+    /// itself are reported to the error stream; errors from any Lox code it
+    /// runs are returned.
+    fn run_command(&mut self, command: &str) -> Result<(), InterpretError> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match (name, arg) {
+            ("globals", "") => {
+                for (name, value) in self.vm.globals() {
+                    writeln!(self.vm.out_stream(), "{name} = {value}").unwrap();
+                }
+            }
+            ("dis", code) if !code.is_empty() => {
+                // Let expressions leave off their `;`, as at the prompt. Only
+                // fall back to the code as given if that compiles and adding
+                // the `;` doesn't, so otherwise errors are reported as for an
+                // expression.
+                let statement = format!("{code};");
+                let source = if !self.vm.compiles(&statement) && self.vm.compiles(code) {
+                    code.to_string()
+                } else {
+                    statement
+                };
+                let assembly = self.vm.disassemble(&source)?;
+                write!(self.vm.out_stream(), "{assembly}").unwrap();
+            }
+            ("load", path) if !path.is_empty() => match fs::read_to_string(path) {
+                Ok(source) => return self.timed(|vm| vm.eval(&source)),
+                Err(err) => writeln!(self.vm.err_stream(), "{path}: {err}").unwrap(),
+            },
+            ("reset", "") => {
+                self.vm.reset();
+                self.buffer.clear();
+            }
+            ("time", "") => {
+                self.time = !self.time;
+                let state = if self.time { "on" } else { "off" };
+                writeln!(self.vm.out_stream(), "Timing is {state}.").unwrap();
+            }
+            ("trace", "on") => self.vm.set_trace(true),
+            ("trace", "off") => self.vm.set_trace(false),
+            ("help", "") => writeln!(self.vm.out_stream(), "{HELP}").unwrap(),
+            _ => writeln!(
+                self.vm.err_stream(),
+                "Unknown command ':{command}'. Type :help for a list."
+            )
+            .unwrap(),
+        }
+        Ok(())
+    }
+
+    /// Run `eval` on the VM, writing how long it took if timing is on.
+    fn timed<F>(&mut self, eval: F) -> Result<(), InterpretError>
+    where
+        F: FnOnce(&mut Vm<O, E>) -> Result<(), InterpretError>,
+    {
+        let start = Instant::now();
+        let result = eval(&mut self.vm);
+        if self.time {
+            writeln!(self.vm.err_stream(), "[{:.3?}]", start.elapsed()).unwrap();
+        }
+        result
+    }
+
+    /// Throw away any incomplete input, as when the user presses Ctrl-C.
+    pub fn cancel(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn vm(&mut self) -> &mut Vm<O, E> {
+        &mut self.vm
+    }
+
+    pub fn into_vm(self) -> Vm<O, E> {
+        self.vm
+    }
+}
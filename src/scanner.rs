@@ -1,12 +1,19 @@
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
 use rstest::*;
 macro_rules! identifier_chars {
     () => {'_' | 'a'..='z' | 'A'..='Z'};
 }
 
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     idx: usize,
     line: u32,
+    /// Byte index of the first character on the current line.
+    line_start: usize,
 }
 impl<'a> Scanner<'a> {
     pub fn new(source: &str) -> Scanner {
@@ -14,22 +21,29 @@ impl<'a> Scanner<'a> {
             source,
             idx: 0,
             line: 1,
+            line_start: 0,
         }
     }
 
     fn make_token_data(&self, token: Token) -> TokenData<'a> {
-        self.make_token_data_with_start(token, self.idx - 1)
+        self.make_token_data_with_start(token, self.source.floor_char_boundary(self.idx - 1))
     }
 
     fn make_token_data_with_start(&self, token: Token, start: usize) -> TokenData<'a> {
         TokenData {
             token,
             line: self.line,
+            column: self.column(start),
             source: &self.source[start..self.source.ceil_char_boundary(self.idx)],
             start,
         }
     }
 
+    /// The 1-based column, in characters, of byte `idx` on the current line.
+    fn column(&self, idx: usize) -> usize {
+        self.source[self.line_start..idx].chars().count() + 1
+    }
+
     fn match_char(&mut self, c: char) -> bool {
         self.match_condition(|ch| ch == c)
     }
@@ -59,6 +73,10 @@ impl<'a> Scanner<'a> {
                     if c == '"' {
                         return self.make_token_data_with_start(Token::String, start);
                     }
+                    if c == '\\' {
+                        // An escaped quote doesn't end the string.
+                        self.next_char();
+                    }
                 }
                 None => {
                     return self.make_token_data_with_start(
@@ -110,12 +128,15 @@ impl<'a> Scanner<'a> {
             }
         } {
             "and" => Token::And,
+            "break" => Token::Break,
             "class" => Token::Class,
+            "continue" => Token::Continue,
             "else" => Token::Else,
             "false" => Token::False,
             "for" => Token::For,
             "fun" => Token::Fun,
             "if" => Token::If,
+            "in" => Token::In,
             "nil" => Token::Nil,
             "or" => Token::Or,
             "print" => Token::Print,
@@ -158,9 +179,13 @@ impl<'a> Scanner<'a> {
 
     pub fn peek(&mut self) -> TokenData<'a> {
         let saved_idx = self.idx;
+        let saved_line = self.line;
+        let saved_line_start = self.line_start;
         let next_char = self.next_char();
         let result = self.char_to_token_data(next_char);
         self.idx = saved_idx;
+        self.line = saved_line;
+        self.line_start = saved_line_start;
         result
     }
 
@@ -171,8 +196,11 @@ impl<'a> Scanner<'a> {
                 ')' => self.make_token_data(Token::RightParen),
                 '{' => self.make_token_data(Token::LeftBrace),
                 '}' => self.make_token_data(Token::RightBrace),
+                '[' => self.make_token_data(Token::LeftBracket),
+                ']' => self.make_token_data(Token::RightBracket),
                 ';' => self.make_token_data(Token::Semicolon),
                 ',' => self.make_token_data(Token::Comma),
+                ':' => self.make_token_data(Token::Colon),
                 '.' => self.make_token_data(Token::Dot),
                 '-' => self.make_token_data(Token::Minus),
                 '+' => self.make_token_data(Token::Plus),
@@ -186,7 +214,7 @@ impl<'a> Scanner<'a> {
                 }
                 '*' => self.make_token_data(Token::Star),
                 '!' => {
-                    let start = self.idx;
+                    let start = self.idx - 1;
                     if self.match_char('=') {
                         self.make_token_data_with_start(Token::BangEqual, start)
                     } else {
@@ -194,7 +222,7 @@ impl<'a> Scanner<'a> {
                     }
                 }
                 '=' => {
-                    let start = self.idx;
+                    let start = self.idx - 1;
                     if self.match_char('=') {
                         self.make_token_data_with_start(Token::EqualEqual, start)
                     } else {
@@ -202,7 +230,7 @@ impl<'a> Scanner<'a> {
                     }
                 }
                 '<' => {
-                    let start = self.idx;
+                    let start = self.idx - 1;
                     if self.match_char('=') {
                         self.make_token_data_with_start(Token::LessEqual, start)
                     } else {
@@ -210,8 +238,9 @@ impl<'a> Scanner<'a> {
                     }
                 }
                 '>' => {
+                    let start = self.idx - 1;
                     if self.match_char('=') {
-                        self.make_token_data(Token::GreaterEqual)
+                        self.make_token_data_with_start(Token::GreaterEqual, start)
                     } else {
                         self.make_token_data(Token::Greater)
                     }
@@ -219,6 +248,7 @@ impl<'a> Scanner<'a> {
                 ' ' | '\r' | '\t' => self.next(),
                 '\n' => {
                     self.line += 1;
+                    self.line_start = self.idx;
                     self.next()
                 }
                 '"' => self.string(),
@@ -229,6 +259,7 @@ impl<'a> Scanner<'a> {
             None => TokenData {
                 token: Token::Eof,
                 line: self.line,
+                column: self.column(self.idx),
                 source: "",
                 start: self.idx,
             },
@@ -236,24 +267,126 @@ impl<'a> Scanner<'a> {
     }
 }
 
+/// Whether `source` stops partway through a construct, with a string left
+/// open or more `(`, `{` or `[` than closing ones, so a REPL should read more
+/// before compiling it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth: usize = 0;
+    loop {
+        match scanner.next().token {
+            Token::LeftParen | Token::LeftBrace | Token::LeftBracket => depth += 1,
+            Token::RightParen | Token::RightBrace | Token::RightBracket => {
+                depth = depth.saturating_sub(1)
+            }
+            Token::Error(ErrorToken::UnterminatedString) => return true,
+            Token::Eof => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+/// The contents of a string literal, between its quotes, with each escape
+/// sequence replaced by the character it stands for. An invalid escape is
+/// reported with its byte range in `literal`.
+pub fn unescape(literal: &str) -> Result<String, (Range<usize>, String)> {
+    let mut string = String::with_capacity(literal.len());
+    let mut chars = literal.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        let unescaped = match chars.next() {
+            Some((_, 'n')) => Some('\n'),
+            Some((_, 't')) => Some('\t'),
+            Some((_, 'r')) => Some('\r'),
+            Some((_, '0')) => Some('\0'),
+            Some((_, '"')) => Some('"'),
+            Some((_, '\\')) => Some('\\'),
+            Some((_, 'u')) => unicode_escape(&mut chars),
+            _ => None,
+        };
+        match unescaped {
+            Some(c) => string.push(c),
+            None => {
+                let end = chars.peek().map_or(literal.len(), |&(end, _)| end);
+                let escape = &literal[start..end];
+                return Err((start..end, format!("Invalid escape sequence '{escape}'.")));
+            }
+        }
+    }
+    Ok(string)
+}
+
+/// The character named by a `{1F600}` following `\u`, of one to six hex
+/// digits.
+fn unicode_escape(chars: &mut Peekable<CharIndices>) -> Option<char> {
+    chars.next_if(|&(_, c)| c == '{')?;
+    let mut code: u32 = 0;
+    let mut digits = 0;
+    while let Some((_, digit)) = chars.next_if(|&(_, c)| c.is_ascii_hexdigit()) {
+        code = code * 16 + digit.to_digit(16).unwrap();
+        digits += 1;
+        if digits > 6 {
+            return None;
+        }
+    }
+    chars.next_if(|&(_, c)| c == '}')?;
+    if digits == 0 {
+        return None;
+    }
+    char::from_u32(code)
+}
+
+#[rstest]
+#[case::plain("héllo", "héllo")]
+#[case::escapes(r#"a\tb\nc\r\0\"\\"#, "a\tb\nc\r\0\"\\")]
+#[case::unicode(r"\u{1F600}\u{e9}", "😀é")]
+fn unescape_replaces_escapes(#[case] literal: &str, #[case] expected: &str) {
+    assert_eq!(unescape(literal).unwrap(), expected);
+}
+
+#[rstest]
+#[case::unknown(r"ab\q", 2..4, r"\q")]
+#[case::no_braces(r"\u00e9", 0..2, r"\u")]
+#[case::empty(r"\u{}x", 0..4, r"\u{}")]
+#[case::too_long(r"\u{1234567}", 0..10, r"\u{1234567")]
+#[case::surrogate(r"\u{D800}", 0..8, r"\u{D800}")]
+#[case::out_of_range(r"\u{110000}", 0..10, r"\u{110000}")]
+#[case::trailing_backslash("a\\", 1..2, "\\")]
+fn unescape_rejects_invalid_escapes(
+    #[case] literal: &str,
+    #[case] range: Range<usize>,
+    #[case] escape: &str,
+) {
+    let message = format!("Invalid escape sequence '{escape}'.");
+    assert_eq!(unescape(literal), Err((range, message)));
+}
+
 #[rstest]
-#[case("1", vec![TokenData {token: Token::Number, source: "1", start: 0, line: 1}])]
+#[case("1", vec![TokenData {token: Token::Number, source: "1", start: 0, line: 1, column: 1}])]
 #[case("1 2 \n", vec![
-    TokenData {token: Token::Number, source: "1", start: 0, line: 1},
-    TokenData {token: Token::Number, source: "2", start: 2, line: 1},
-    TokenData {token: Token::Eof, source: "", start: 5, line: 2},
+    TokenData {token: Token::Number, source: "1", start: 0, line: 1, column: 1},
+    TokenData {token: Token::Number, source: "2", start: 2, line: 1, column: 3},
+    TokenData {token: Token::Eof, source: "", start: 5, line: 2, column: 1},
 ])]
-#[case("true", vec![TokenData {token: Token::True, source: "true", start: 0, line: 1}])]
+#[case("true", vec![TokenData {token: Token::True, source: "true", start: 0, line: 1, column: 1}])]
 #[case("1 + 2\n", vec![
-    TokenData {token: Token::Number, source: "1", start: 0, line: 1},
-    TokenData {token: Token::Plus, source: "+", start: 2, line: 1},
-    TokenData {token: Token::Number, source: "2", start: 4, line: 1},
-    TokenData {token: Token::Eof, source: "", start: 6, line: 2},
+    TokenData {token: Token::Number, source: "1", start: 0, line: 1, column: 1},
+    TokenData {token: Token::Plus, source: "+", start: 2, line: 1, column: 3},
+    TokenData {token: Token::Number, source: "2", start: 4, line: 1, column: 5},
+    TokenData {token: Token::Eof, source: "", start: 6, line: 2, column: 1},
 ])]
 #[case("  \"two\"  \"strings\" \n", vec![
-    TokenData {token: Token::String, source: "\"two\"", start: 2, line: 1},
-    TokenData {token: Token::String, source: "\"strings\"", start: 9, line: 1},
-    TokenData {token: Token::Eof, source: "", start: 20, line: 2}])]
+    TokenData {token: Token::String, source: "\"two\"", start: 2, line: 1, column: 3},
+    TokenData {token: Token::String, source: "\"strings\"", start: 9, line: 1, column: 10},
+    TokenData {token: Token::Eof, source: "", start: 20, line: 2, column: 1}])]
+#[case("a\n  b >= c", vec![
+    TokenData {token: Token::Identifier, source: "a", start: 0, line: 1, column: 1},
+    TokenData {token: Token::Identifier, source: "b", start: 4, line: 2, column: 3},
+    TokenData {token: Token::GreaterEqual, source: ">=", start: 6, line: 2, column: 5},
+    TokenData {token: Token::Identifier, source: "c", start: 9, line: 2, column: 8}])]
 fn scanner(#[case] source: &str, #[case] expected_tokens: Vec<TokenData>) {
     let mut scanner = Scanner::new(source);
 
@@ -270,6 +403,9 @@ pub enum Token {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -292,12 +428,15 @@ pub enum Token {
     Number,
     // keywords.
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
@@ -338,6 +477,21 @@ impl ErrorToken {
 pub struct TokenData<'a> {
     pub token: Token,
     pub line: u32,
+    pub column: usize,
     pub source: &'a str,
     pub start: usize,
 }
+
+#[rstest]
+#[case("print 1;", false)]
+#[case("fun f() {", true)]
+#[case("fun f() {\n  print (1 +", true)]
+#[case("fun f() { print 1; }", false)]
+#[case("print \"abc", true)]
+#[case("print \"{\";", false)]
+#[case("print \"\\\"{\";", false)]
+#[case("print \"a\\\"", true)]
+#[case("}}", false)]
+fn detects_incomplete_input(#[case] source: &str, #[case] expected: bool) {
+    assert_eq!(is_incomplete(source), expected);
+}
@@ -1,16 +1,59 @@
-use std::{collections::HashSet, rc::Rc};
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    hash::{Hash, Hasher},
+};
 
 use rstest::rstest;
 
-pub struct Strings(HashSet<Rc<String>>);
+use crate::memory::{Gc, Heap};
+
+/// Interned strings, looked up by content. The table holds its strings
+/// weakly: any that are unmarked when the heap is collected are dropped.
+pub struct Strings(HashSet<Interned>);
+
+struct Interned(Gc<String>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0 == *other.0
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state)
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
 
 impl Strings {
     pub fn new() -> Self {
         Strings(HashSet::new())
     }
 
-    pub fn new_string(&mut self, string_data: String) -> Rc<String> {
-        self.0.get_or_insert(Rc::new(string_data)).clone()
+    pub fn new_string(&mut self, heap: &mut Heap, string_data: String) -> Gc<String> {
+        if let Some(interned) = self.0.get(string_data.as_str()) {
+            return interned.0;
+        }
+        let string = heap.alloc(string_data);
+        self.0.insert(Interned(string));
+        string
+    }
+
+    pub fn get(&self, string: &str) -> Option<Gc<String>> {
+        self.0.get(string).map(|interned| interned.0)
+    }
+
+    pub fn remove_unmarked(&mut self) {
+        self.0.retain(|interned| interned.0.is_marked());
     }
 
     pub fn len(&self) -> usize {
@@ -20,15 +63,17 @@ impl Strings {
 
 #[rstest]
 fn string_interning_works() {
+    let mut heap = Heap::new();
     let mut strings = Strings::new();
     assert_eq!(strings.len(), 0);
 
-    strings.new_string("asdf".to_string());
+    strings.new_string(&mut heap, "asdf".to_string());
     assert_eq!(strings.len(), 1);
 
-    strings.new_string("zxcv".to_string());
+    strings.new_string(&mut heap, "zxcv".to_string());
     assert_eq!(strings.len(), 2);
 
-    strings.new_string("asdf".to_string());
+    strings.new_string(&mut heap, "asdf".to_string());
     assert_eq!(strings.len(), 2); // New string should not be added
+    assert_eq!(heap.len(), 2);
 }
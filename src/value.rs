@@ -1,7 +1,8 @@
+use crate::memory::{Trace, Tracer};
 use crate::object::Object;
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     Bool(bool),
     Nil,
@@ -23,3 +24,11 @@ impl fmt::Display for Value {
         )
     }
 }
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Value::Obj(object) = self {
+            object.trace(tracer);
+        }
+    }
+}
@@ -1,23 +1,29 @@
-use crate::chunk::{Chunk, Op};
+use crate::chunk::{Chunk, LoadError, Op};
 use crate::compiler;
-use crate::object::{Function, Object};
+use crate::diagnostic::{self, Diagnostic};
+use crate::handle::{Handle, Handles};
+use crate::memory::{Gc, Heap, Trace, Tracer};
+use crate::natives::{self, NativeContext};
+use crate::object::{
+    self, BoundMethod, BoundNative, Class, Closure, Function, Instance, Iter, List, Map, Native,
+    NativeFn, Object, Upvalue,
+};
 use crate::strings::Strings;
 use crate::value::Value;
-use std::cmp;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fmt::Write as FmtWrite;
-use std::io::Write;
-use std::rc::Rc;
-use std::slice::Iter;
+use std::fmt;
+use std::io::{self, Write};
+use std::slice;
+
+const FRAMES_MAX: usize = 64;
 
 pub fn interpret<O: std::io::Write, E: std::io::Write>(
     source: &str,
     out_stream: &mut O,
     err_stream: &mut E,
 ) -> Result<(), InterpretError> {
-    let (function, objects, strings) =
-        compiler::compile(source).map_err(|_| InterpretError::CompileError)?;
-    VM::new(function, objects, strings, out_stream, err_stream).run()
+    Vm::new(out_stream, err_stream).eval(source)
 }
 
 macro_rules! bin_op {
@@ -30,7 +36,7 @@ macro_rules! bin_op {
 
             $self.push(Value::Number(b $op a));
         } else {
-            $self.runtime_error("Operands must be numbers.".to_string());
+            return Result::Err($self.runtime_error("Operands must be numbers.".to_string()));
         }
     };
 }
@@ -45,60 +51,415 @@ macro_rules! bool_bin_op {
 
             $self.push(Value::Bool(b $op a));
         } else {
-            $self.runtime_error("Operands must be numbers.".to_string());
+            return Result::Err($self.runtime_error("Operands must be numbers.".to_string()));
         }
     };
 }
 
-pub struct VM<'a, O: Write, E: Write> {
+/// A Lox interpreter session. Globals, interned strings and the heap persist
+/// across calls to `eval`, so a `Vm` can back a REPL or be embedded in a host
+/// program.
+///
+/// Values are handed to and from the host as `Handle`s, which keep the
+/// objects they refer to alive across further calls into the `Vm`.
+pub struct Vm<O: Write, E: Write> {
+    stack: Vec<Value>,
     frames: Vec<CallFrame>,
-    objects: Vec<Object>,
+    /// Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<Gc<RefCell<Upvalue>>>,
+    heap: Heap,
     strings: Strings,
-    globals: HashMap<Rc<String>, Value>,
-    out_stream: &'a mut O,
-    err_stream: &'a mut E,
+    globals: HashMap<Gc<String>, Value>,
+    /// The methods every list has, by name.
+    list_methods: HashMap<Gc<String>, Gc<Native>>,
+    /// The methods every string has, by name.
+    string_methods: HashMap<Gc<String>, Gc<Native>>,
+    /// The methods every map has, by name.
+    map_methods: HashMap<Gc<String>, Gc<Native>>,
+    init_string: Gc<String>,
+    /// The names of the methods a `for-in` loop calls on an instance.
+    iter_string: Gc<String>,
+    next_string: Gc<String>,
+    /// Values the host holds handles to.
+    handles: Handles,
+    out_stream: O,
+    err_stream: E,
+    /// Whether to write each compiled chunk and executed instruction to the
+    /// error stream.
+    trace: bool,
 }
-impl<'a, O: Write, E: Write> VM<'a, O, E> {
-    fn new(
-        function: Function,
-        objects: Vec<Object>,
-        strings: Strings,
-        out_stream: &'a mut O,
-        err_stream: &'a mut E,
-    ) -> VM<'a, O, E> {
-        VM {
-            objects,
+impl<O: Write, E: Write> Vm<O, E> {
+    pub fn new(out_stream: O, err_stream: E) -> Vm<O, E> {
+        let mut heap = Heap::new();
+        let mut strings = Strings::new();
+        let init_string = strings.new_string(&mut heap, "init".to_string());
+        let iter_string = strings.new_string(&mut heap, "iter".to_string());
+        let next_string = strings.new_string(&mut heap, "next".to_string());
+        let mut vm = Vm {
+            heap,
             strings,
-            frames: vec![CallFrame::new(function, vec![])],
+            stack: vec![],
+            frames: vec![],
+            open_upvalues: vec![],
             globals: HashMap::new(),
+            list_methods: HashMap::new(),
+            string_methods: HashMap::new(),
+            map_methods: HashMap::new(),
+            init_string,
+            iter_string,
+            next_string,
+            handles: Handles::new(),
             out_stream,
             err_stream,
+            trace: false,
+        };
+        vm.define_builtins();
+        for (name, arity, function) in natives::LIST_METHODS {
+            let (name, native) = vm.new_native(name, arity, Box::new(function));
+            vm.list_methods.insert(name, native);
+        }
+        for (name, arity, function) in natives::STRING_METHODS {
+            let (name, native) = vm.new_native(name, arity, Box::new(function));
+            vm.string_methods.insert(name, native);
+        }
+        for (name, arity, function) in natives::MAP_METHODS {
+            let (name, native) = vm.new_native(name, arity, Box::new(function));
+            vm.map_methods.insert(name, native);
+        }
+        vm
+    }
+
+    fn define_builtins(&mut self) {
+        self.define_builtin("clock", 0, Box::new(natives::clock()));
+        self.define_builtin("sleep", 1, Box::new(natives::sleep));
+        self.define_builtin("input", 0, Box::new(natives::input));
+        self.define_builtin("exit", 1, Box::new(natives::exit));
+        self.define_builtin("range", 2, Box::new(natives::range));
+    }
+
+    /// Forget every global, including natives added with `define_native`,
+    /// leaving only the built-in natives, as in a new `Vm`.
+    pub fn reset(&mut self) {
+        self.reset_stack();
+        self.globals.clear();
+        self.define_builtins();
+    }
+
+    /// Compile and run `source` as a script in this session. Errors are
+    /// written to the error stream, with the offending source line, as well
+    /// as being returned.
+    pub fn eval(&mut self, source: &str) -> Result<(), InterpretError> {
+        let function = self.compile_source(source, false)?;
+        self.run_script(function)
+    }
+
+    /// Like `eval`, but for a line typed into a REPL: top-level expression
+    /// statements print their value, and the last may leave off its `;`.
+    pub fn eval_repl(&mut self, source: &str) -> Result<(), InterpretError> {
+        let function = self.compile_source(source, true)?;
+        self.run_script(function)
+    }
+
+    /// Compile `source` to `.loxc` bytecode, for `eval_bytecode` to run
+    /// later without parsing it again.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, InterpretError> {
+        let function = self.compile_source(source, false)?;
+        let mut bytes = vec![];
+        function
+            .chunk
+            .write_to(&mut bytes)
+            .expect("compiled chunks only hold serializable constants");
+        Ok(bytes)
+    }
+
+    /// Load a script written by `compile` and run it in this session.
+    pub fn eval_bytecode<R: io::Read>(&mut self, reader: &mut R) -> Result<(), InterpretError> {
+        let chunk = match Chunk::read_from(reader, &mut self.heap, &mut self.strings) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                writeln!(self.err_stream, "{err}").unwrap();
+                return Err(InterpretError::LoadError(err));
+            }
+        };
+        let mut function = Function::new(None);
+        function.chunk = chunk;
+        let function = self.alloc(function);
+        self.run_script(function)
+    }
+
+    /// Assemble `text`, written in the dialect described in `chunk::asm`,
+    /// and run it as a script in this session. The code is verified before
+    /// it runs.
+    pub fn eval_assembly(&mut self, text: &str) -> Result<(), InterpretError> {
+        let function = self.assemble_source(text)?;
+        self.run_script(function)
+    }
+
+    /// Assemble `text` to `.loxc` bytecode.
+    pub fn assemble(&mut self, text: &str) -> Result<Vec<u8>, InterpretError> {
+        let function = self.assemble_source(text)?;
+        let mut bytes = vec![];
+        function
+            .chunk
+            .write_to(&mut bytes)
+            .expect("assembled chunks only hold serializable constants");
+        Ok(bytes)
+    }
+
+    /// Compile `source` and return it in the assembly dialect read by
+    /// `eval_assembly`.
+    pub fn disassemble(&mut self, source: &str) -> Result<String, InterpretError> {
+        let function = self.compile_source(source, false)?;
+        Ok(function
+            .chunk
+            .to_assembly()
+            .expect("compiled chunks only hold literal constants"))
+    }
+
+    /// Compile `source` and return it as JSON, in the format described in
+    /// `chunk::json`.
+    pub fn dump_json(&mut self, source: &str) -> Result<String, InterpretError> {
+        let function = self.compile_source(source, false)?;
+        Ok(function.chunk.dump_json())
+    }
+
+    fn compile_source(
+        &mut self,
+        source: &str,
+        repl: bool,
+    ) -> Result<Gc<Function>, InterpretError> {
+        match self.compile_quietly(source, repl) {
+            Ok(function) => {
+                if self.trace {
+                    let listing = function.chunk.disassemble("<script>");
+                    write!(self.err_stream, "{listing}").unwrap();
+                }
+                Ok(function)
+            }
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    write!(self.err_stream, "{}", diagnostic.render(source)).unwrap();
+                }
+                Err(InterpretError::CompileError(diagnostics))
+            }
         }
     }
 
-    fn run(&mut self) -> Result<(), InterpretError> {
+    /// Whether `source` compiles as a script, without reporting any errors.
+    pub(crate) fn compiles(&mut self, source: &str) -> bool {
+        self.compile_quietly(source, false).is_ok()
+    }
+
+    fn compile_quietly(
+        &mut self,
+        source: &str,
+        repl: bool,
+    ) -> Result<Gc<Function>, Vec<Diagnostic>> {
+        let (heap, strings, roots) = self.split();
+        compiler::compile(source, heap, strings, &|tracer| roots.trace(tracer), repl)
+    }
+
+    fn assemble_source(&mut self, text: &str) -> Result<Gc<Function>, InterpretError> {
+        let chunk = match Chunk::assemble(text, &mut self.heap, &mut self.strings) {
+            Ok(chunk) => chunk,
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    write!(self.err_stream, "{}", diagnostic.render(text)).unwrap();
+                }
+                return Err(InterpretError::CompileError(diagnostics));
+            }
+        };
+        if let Err(errors) = chunk.verify() {
+            let err = LoadError::Invalid(errors);
+            writeln!(self.err_stream, "{err}").unwrap();
+            return Err(InterpretError::LoadError(err));
+        }
+        let mut function = Function::new(None);
+        function.chunk = chunk;
+        Ok(self.alloc(function))
+    }
+
+    fn run_script(&mut self, function: Gc<Function>) -> Result<(), InterpretError> {
+        let closure = self.alloc(Closure::new(function, vec![]));
+        self.push(Value::Obj(Object::Closure(closure)));
+        self.frames.push(CallFrame::new(closure, 0));
+        match self.run(0) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                self.reset_stack();
+                Err(err)
+            }
+        }
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Handle> {
+        let value = self.lookup_global(name)?;
+        Some(self.handles.handle(value))
+    }
+
+    fn lookup_global(&self, name: &str) -> Option<Value> {
+        let name = self.strings.get(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    /// Define the global `name`, or assign to it if it exists.
+    ///
+    /// # Panics
+    ///
+    /// If `value` refers to an object belonging to another `Vm`.
+    pub fn set_global(&mut self, name: &str, value: &Handle) {
+        // The handle keeps the value reachable while the name is interned.
+        let value = self.handles.value(value);
+        let name = self.intern(name.to_string());
+        self.globals.insert(name, value);
+    }
+
+    /// Make a Lox string, e.g. to pass to `set_global` or `call_function`.
+    pub fn new_string(&mut self, string: String) -> Handle {
+        let chars = self.intern(string);
+        self.handles.handle(Value::Obj(Object::String { chars }))
+    }
+
+    /// Call the global function `name` with `args` and return its result.
+    ///
+    /// # Panics
+    ///
+    /// If an argument refers to an object belonging to another `Vm`.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[Handle],
+    ) -> Result<Handle, InterpretError> {
+        let args: Vec<Value> = args.iter().map(|arg| self.handles.value(arg)).collect();
+        let callee = match self.lookup_global(name) {
+            Some(callee) => callee,
+            None => return Err(self.runtime_error(format!("Undefined variable '{name}'."))),
+        };
+        if args.len() > u8::MAX as usize {
+            return Err(self.runtime_error("Can't have more than 255 arguments.".to_string()));
+        }
+
+        self.push(callee);
+        for arg in &args {
+            self.push(*arg);
+        }
+        let result = self
+            .call_value(callee, args.len() as u8)
+            .and_then(|()| {
+                if self.frames.is_empty() {
+                    // Natives and classes without initializers finish immediately.
+                    Ok(self.pop())
+                } else {
+                    self.run(0)
+                }
+            });
+        match result {
+            Ok(value) => Ok(self.handles.handle(value)),
+            Err(err) => {
+                self.reset_stack();
+                Err(err)
+            }
+        }
+    }
+
+    pub fn set_gc_growth_factor(&mut self, growth_factor: usize) {
+        self.heap.set_growth_factor(growth_factor);
+    }
+
+    /// Write a listing of each script as it is compiled, and every
+    /// instruction with the stack as it runs, to the error stream.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Every global and its value, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Handle)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.to_string(), self.handles.handle(*value)))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    pub(crate) fn out_stream(&mut self) -> &mut O {
+        &mut self.out_stream
+    }
+
+    pub(crate) fn err_stream(&mut self) -> &mut E {
+        &mut self.err_stream
+    }
+
+    pub fn into_streams(self) -> (O, E) {
+        (self.out_stream, self.err_stream)
+    }
+
+    /// Make the Rust function `function` callable from Lox as the global
+    /// `name`, taking exactly `arity` arguments.
+    ///
+    /// A native that returns a handle to an object belonging to another `Vm`
+    /// panics.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut NativeContext, &[Handle]) -> Result<Handle, String> + 'static,
+    {
+        let function = move |context: &mut NativeContext, args: &[Value]| {
+            let args: Vec<Handle> = args.iter().map(|&arg| context.handles.handle(arg)).collect();
+            let result = function(context, &args)?;
+            Ok(context.handles.value(&result))
+        };
+        self.define_builtin(name, arity, Box::new(function));
+    }
+
+    fn define_builtin(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let (name, native) = self.new_native(name, arity, function);
+        self.globals.insert(name, Value::Obj(Object::Native(native)));
+    }
+
+    fn new_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: NativeFn,
+    ) -> (Gc<String>, Gc<Native>) {
+        let name = self.intern(name.to_string());
+        // Keep the name reachable while the native itself is allocated.
+        self.push(Value::Obj(Object::String { chars: name }));
+        let native = self.alloc(Native::new(name.to_string(), arity, function));
+        self.pop();
+        (name, native)
+    }
+
+    /// Run until only `depth` frames are left, producing the return value of
+    /// the last frame to return.
+    fn run(&mut self, depth: usize) -> Result<Value, InterpretError> {
         loop {
             let (op, op_size) = self
-                .current_frame()
-                .function
-                .chunk
+                .current_chunk()
                 .decode(self.current_frame().ip);
-            if cfg!(feature = "trace") {
-                self.current_frame()
-                    .function
-                    .chunk
+            if self.trace {
+                let mut trace = self
+                    .current_chunk()
                     .disassemble_code(self.current_frame().ip);
-                print!("          ");
+                trace.push_str("          ");
                 for val in self.iter() {
-                    print!("[{val}]");
+                    trace.push_str(&format!("[{val}]"));
                 }
-                println!();
+                writeln!(self.err_stream, "{trace}").unwrap();
             }
-            let mut ip_offset = 0;
+            self.current_frame_mut().ip += op_size;
             match op {
                 Op::Constant { value } => self.push(value),
                 Op::Return => {
-                 return Result::Ok(());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == depth {
+                        return Result::Ok(result);
+                    }
+                    self.push(result);
                 }
                 Op::Print => {
                     let val = self.pop();
@@ -109,7 +470,7 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                         Value::Number(val) => {
                             let val = *val;
                             self.pop();
-                            self.current_frame_mut().slots.push(Value::Number(-val));
+                            self.push(Value::Number(-val));
                         }
                         _ => {
                             return Result::Err(self.runtime_error("Operand must be a number.".to_string()));
@@ -129,10 +490,12 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                     self.pop();
                 }
                 Op::GetLocal { idx } => {
-                    self.push(self.current_frame().slots[idx as usize].clone());
+                    let slot = self.current_frame().slots + idx as usize;
+                    self.push(self.stack[slot]);
                 }
                 Op::SetLocal { idx } => {
-                    self.current_frame_mut().slots[idx as usize] = self.peek(0).clone();
+                    let slot = self.current_frame().slots + idx as usize;
+                    self.stack[slot] = *self.peek(0);
                 }
                 Op::GetGlobal { name } => {
                     match self.globals.get(&name) {
@@ -147,8 +510,8 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                     self.globals.insert(name, val);
                 }
                 Op::SetGlobal { name } => {
-                    if self.globals.contains_key(&*name) {
-                        self.globals.insert(name, self.peek(0).clone());
+                    if self.globals.contains_key(&name) {
+                        self.globals.insert(name, *self.peek(0));
                     } else {
                         return Result::Err(self.runtime_error(format!("Undefined variable '{}'.", name)));
                     }
@@ -159,8 +522,11 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                        let Value::Obj(y) = self.peek(1) &&
                         let Object::String { chars: b } = y
                     {
-                        let new_string = self.strings.new_string((**b).to_owned() + &**a);
-                        self.current_frame_mut().slots.push(Value::Obj(Object::String { chars: new_string }));
+                        let new_string = (**b).to_owned() + &**a;
+                        let new_string = self.intern(new_string);
+                        self.pop();
+                        self.pop();
+                        self.push(Value::Obj(Object::String { chars: new_string }));
                     } else {
                         bin_op!(self, +);
                     }
@@ -176,7 +542,7 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                 }
                 Op::Not => {
                     let bool = Value::Bool(is_falsey(&self.pop()));
-                    self.current_frame_mut().slots.push(bool);
+                    self.push(bool);
                 }
                 Op::Equal => {
                     let a = self.pop();
@@ -191,52 +557,617 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
                 }
                 Op::JumpIfFalse { offset } => {
                     if is_falsey(self.peek(0)) {
-                        ip_offset = offset as isize;
+                        self.current_frame_mut().ip += offset as usize;
                     }
                 }
                 Op::Jump { offset } => {
-                    ip_offset = offset as isize;
+                    self.current_frame_mut().ip += offset as usize;
                 }
                 Op::Loop { offset } => {
-                    ip_offset = -(offset as isize);
+                    self.current_frame_mut().ip -= offset as usize;
+                }
+                Op::Call { arg_count } => {
+                    self.call_value(*self.peek(arg_count as usize), arg_count)?;
+                }
+                Op::Closure { function, upvalues } => {
+                    let upvalues = upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                let slot = self.current_frame().slots + upvalue.index as usize;
+                                self.capture_upvalue(slot)
+                            } else {
+                                self.current_frame().closure.upvalues[upvalue.index as usize]
+                            }
+                        })
+                        .collect();
+                    let closure = self.alloc(Closure::new(function, upvalues));
+                    self.push(Value::Obj(Object::Closure(closure)));
+                }
+                Op::GetUpvalue { idx } => {
+                    let upvalue = self.current_frame().closure.upvalues[idx as usize];
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot],
+                        Upvalue::Closed(value) => *value,
+                    };
+                    self.push(value);
+                }
+                Op::SetUpvalue { idx } => {
+                    let upvalue = self.current_frame().closure.upvalues[idx as usize];
+                    let value = *self.peek(0);
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Op::Class { name } => {
+                    let class = self.alloc(Class::new(name));
+                    self.push(Value::Obj(Object::Class(class)));
+                }
+                Op::GetProperty { name } if self.native_methods(self.peek(0)).is_some() => {
+                    self.bind_native_method(name)?;
+                }
+                Op::GetProperty { name } => {
+                    let instance = match self.peek(0) {
+                        Value::Obj(Object::Instance(instance)) => *instance,
+                        _ => {
+                            return Result::Err(
+                                self.runtime_error("Only instances have properties.".to_string()),
+                            );
+                        }
+                    };
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                Op::SetProperty { name } => {
+                    let instance = match self.peek(1) {
+                        Value::Obj(Object::Instance(instance)) => *instance,
+                        _ => {
+                            return Result::Err(
+                                self.runtime_error("Only instances have fields.".to_string()),
+                            );
+                        }
+                    };
+                    let value = self.pop();
+                    instance.fields.borrow_mut().insert(name, value);
+                    self.heap.resize(instance);
+                    self.pop();
+                    self.push(value);
+                }
+                Op::Method { name } => {
+                    // The compiler always emits a closure and its class here,
+                    // but hand-written bytecode may not.
+                    let method = match self.pop() {
+                        Value::Obj(Object::Closure(closure)) => closure,
+                        _ => {
+                            return Err(
+                                self.runtime_error("Only closures can be methods.".to_string())
+                            );
+                        }
+                    };
+                    match self.peek(0) {
+                        Value::Obj(Object::Class(class)) => {
+                            let class = *class;
+                            class.methods.borrow_mut().insert(name, method);
+                            self.heap.resize(class);
+                        }
+                        _ => {
+                            return Err(
+                                self.runtime_error("Only classes can have methods.".to_string())
+                            );
+                        }
+                    }
+                }
+                Op::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Obj(Object::Class(class)) => *class,
+                        _ => {
+                            return Result::Err(
+                                self.runtime_error("Superclass must be a class.".to_string()),
+                            );
+                        }
+                    };
+                    let subclass = match self.peek(0) {
+                        Value::Obj(Object::Class(class)) => *class,
+                        _ => {
+                            return Err(
+                                self.runtime_error("Only classes can inherit.".to_string())
+                            );
+                        }
+                    };
+                    if Gc::ptr_eq(superclass, subclass) {
+                        return Result::Err(
+                            self.runtime_error("A class can't inherit from itself.".to_string()),
+                        );
+                    }
+                    // Methods are copied down before the subclass defines its
+                    // own, so overrides replace the inherited entries.
+                    let methods = superclass.methods.borrow().clone();
+                    subclass.methods.borrow_mut().extend(methods);
+                    self.heap.resize(subclass);
+                    self.pop();
+                }
+                Op::GetSuper { name } => {
+                    let superclass = match self.pop() {
+                        Value::Obj(Object::Class(class)) => class,
+                        _ => {
+                            return Err(
+                                self.runtime_error("Superclass must be a class.".to_string())
+                            );
+                        }
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                Op::BuildList { item_count } => {
+                    let start = self.stack.len() - item_count as usize;
+                    // The items stay on the stack, and reachable, until the
+                    // list holding them is allocated.
+                    let list = self.alloc(List::new(self.stack[start..].to_vec()));
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(Object::List(list)));
+                }
+                Op::BuildMap { entry_count } => {
+                    let start = self.stack.len() - 2 * entry_count as usize;
+                    let map = self.alloc(Map::new());
+                    for entry in self.stack[start..].chunks(2) {
+                        if let Err(message) = map.set(entry[0], entry[1]) {
+                            return Err(self.runtime_error(message));
+                        }
+                    }
+                    self.heap.resize(map);
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(Object::Map(map)));
+                }
+                Op::GetIndex => {
+                    let key = *self.peek(0);
+                    let item = match *self.peek(1) {
+                        Value::Obj(Object::List(list)) => {
+                            list.index(key).map(|index| list.items.borrow()[index])
+                        }
+                        Value::Obj(Object::Map(map)) => match map.get(key) {
+                            Ok(Some(value)) => Ok(value),
+                            Ok(None) => Err(format!("Undefined key {key}.")),
+                            Err(message) => Err(message),
+                        },
+                        // The string stays on the stack while the character
+                        // is interned.
+                        Value::Obj(Object::String { chars }) => {
+                            object::char_at(&chars, key).map(|c| Value::Obj(Object::String {
+                                chars: self.intern(c.to_string()),
+                            }))
+                        }
+                        _ => Err("Only lists, maps and strings can be indexed.".to_string()),
+                    };
+                    match item {
+                        Ok(item) => {
+                            self.pop();
+                            self.pop();
+                            self.push(item);
+                        }
+                        Err(message) => return Err(self.runtime_error(message)),
+                    }
+                }
+                Op::SetIndex => {
+                    let value = *self.peek(0);
+                    let key = *self.peek(1);
+                    let result = match self.peek(2) {
+                        Value::Obj(Object::List(list)) => list
+                            .index(key)
+                            .map(|index| list.items.borrow_mut()[index] = value),
+                        Value::Obj(Object::Map(map)) => {
+                            let map = *map;
+                            map.set(key, value).map(|()| self.heap.resize(map))
+                        }
+                        Value::Obj(Object::String { .. }) => {
+                            Err("Strings are immutable.".to_string())
+                        }
+                        _ => Err("Only lists, maps and strings can be indexed.".to_string()),
+                    };
+                    if let Err(message) = result {
+                        return Err(self.runtime_error(message));
+                    }
+                    self.pop();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Op::GetIter => {
+                    let iterator = self.get_iter(*self.peek(0))?;
+                    self.pop();
+                    self.push(iterator);
+                }
+                Op::ForIter { offset } => match self.iter_next(*self.peek(0))? {
+                    Some(item) => self.push(item),
+                    None => self.current_frame_mut().ip += offset as usize,
+                },
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), InterpretError> {
+        match callee {
+            Value::Obj(Object::Closure(closure)) => self.call(closure, arg_count),
+            Value::Obj(Object::Class(class)) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                let instance = self.alloc(Instance::new(class));
+                self.stack[slot] = Value::Obj(Object::Instance(instance));
+                let initializer = class.methods.borrow().get(&self.init_string).cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(format!(
+                        "Expected 0 arguments but got {arg_count}."
+                    ))),
+                    None => Ok(()),
                 }
             }
-            self.current_frame_mut().ip =
-                (self.current_frame().ip as isize + op_size as isize + ip_offset as isize) as usize;
+            Value::Obj(Object::Native(native)) => self.call_native(native, arg_count, false),
+            Value::Obj(Object::BoundNative(bound)) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = bound.receiver;
+                self.call_native(bound.native, arg_count, true)
+            }
+            Value::Obj(Object::BoundMethod(bound)) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(bound.method, arg_count)
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.".to_string())),
+        }
+    }
+
+    /// Replace the instance on top of the stack with its method `name`, bound
+    /// to that instance.
+    fn bind_method(&mut self, class: Gc<Class>, name: Gc<String>) -> Result<(), InterpretError> {
+        let method = class.methods.borrow().get(&name).cloned();
+        match method {
+            Some(method) => {
+                let receiver = self.pop();
+                let bound = self.alloc(BoundMethod::new(receiver, method));
+                self.push(Value::Obj(Object::BoundMethod(bound)));
+                Ok(())
+            }
+            None => Err(self.runtime_error(format!("Undefined property '{name}'."))),
+        }
+    }
+
+    /// Whether `instance` has a field or method called `name`.
+    fn has_property(&self, instance: Gc<Instance>, name: Gc<String>) -> bool {
+        instance.fields.borrow().contains_key(&name)
+            || instance.class.methods.borrow().contains_key(&name)
+    }
+
+    /// Call `instance`'s method `name` with no arguments, running it to
+    /// completion.
+    fn call_method(
+        &mut self,
+        instance: Gc<Instance>,
+        name: Gc<String>,
+    ) -> Result<Value, InterpretError> {
+        let depth = self.frames.len();
+        self.push(Value::Obj(Object::Instance(instance)));
+        let field = instance.fields.borrow().get(&name).cloned();
+        match field {
+            Some(value) => {
+                self.pop();
+                self.push(value);
+            }
+            None => self.bind_method(instance.class, name)?,
+        }
+        self.call_value(*self.peek(0), 0)?;
+        if self.frames.len() == depth {
+            // Natives and classes without initializers finish immediately.
+            Ok(self.pop())
+        } else {
+            self.run(depth)
+        }
+    }
+
+    /// An iterator over `iterable`, which is on top of the stack: a new
+    /// `Iter` for built-in values, or for an instance, either the instance
+    /// itself if it has a `next` method or whatever its `iter` method
+    /// returns.
+    fn get_iter(&mut self, iterable: Value) -> Result<Value, InterpretError> {
+        let not_iterable = "Only lists, maps, strings and iterators can be looped over.";
+        let instance = match iterable {
+            Value::Obj(Object::Iter(_)) => return Ok(iterable),
+            Value::Obj(Object::Instance(instance)) => instance,
+            _ => {
+                return match Iter::new(iterable) {
+                    Some(iter) => Ok(Value::Obj(Object::Iter(self.alloc(iter)))),
+                    None => Err(self.runtime_error(not_iterable.to_string())),
+                };
+            }
+        };
+        if self.has_property(instance, self.next_string) {
+            return Ok(iterable);
+        }
+        if !self.has_property(instance, self.iter_string) {
+            return Err(self.runtime_error(not_iterable.to_string()));
+        }
+        let iterator = self.call_method(instance, self.iter_string)?;
+        match iterator {
+            Value::Obj(Object::Instance(iterator))
+                if !self.has_property(iterator, self.next_string) =>
+            {
+                Err(self.runtime_error("Iterator has no 'next' method.".to_string()))
+            }
+            Value::Obj(Object::Instance(_)) => Ok(iterator),
+            _ => {
+                // Keep what `iter` returned reachable while it is wrapped.
+                self.push(iterator);
+                let iterator = self.get_iter(iterator);
+                self.pop();
+                iterator
+            }
+        }
+    }
+
+    /// The next item from `iterator`, which is on top of the stack, or
+    /// `None` once it has run out. An instance has run out when its `next`
+    /// method returns nil.
+    fn iter_next(&mut self, iterator: Value) -> Result<Option<Value>, InterpretError> {
+        let iter = match iterator {
+            Value::Obj(Object::Iter(iter)) => iter,
+            Value::Obj(Object::Instance(instance)) => {
+                let item = self.call_method(instance, self.next_string)?;
+                return Ok(if let Value::Nil = item { None } else { Some(item) });
+            }
+            _ => return Err(self.runtime_error("Only iterators can be stepped.".to_string())),
+        };
+        let item = match &*iter {
+            Iter::List { list, next } => {
+                let item = list.items.borrow().get(next.get()).copied();
+                next.set(next.get() + 1);
+                item
+            }
+            Iter::Map { map, next } => {
+                let key = map.key_at(next.get());
+                next.set(next.get() + 1);
+                key
+            }
+            Iter::String { string, next } => match string[next.get()..].chars().next() {
+                Some(c) => {
+                    next.set(next.get() + c.len_utf8());
+                    let chars = self.intern(c.to_string());
+                    Some(Value::Obj(Object::String { chars }))
+                }
+                None => None,
+            },
+            Iter::Range { next, end } => {
+                let item = next.get();
+                next.set(item + 1.0);
+                (item < *end).then_some(Value::Number(item))
+            }
+        };
+        Ok(item)
+    }
+
+    /// The native methods of `value`'s type, if it has any.
+    fn native_methods(&self, value: &Value) -> Option<&HashMap<Gc<String>, Gc<Native>>> {
+        match value {
+            Value::Obj(Object::List(_)) => Some(&self.list_methods),
+            Value::Obj(Object::String { .. }) => Some(&self.string_methods),
+            Value::Obj(Object::Map(_)) => Some(&self.map_methods),
+            _ => None,
+        }
+    }
+
+    /// Replace the list, map or string on top of the stack with its native method
+    /// `name`, bound to it.
+    fn bind_native_method(&mut self, name: Gc<String>) -> Result<(), InterpretError> {
+        let native = self
+            .native_methods(self.peek(0))
+            .and_then(|methods| methods.get(&name).copied());
+        let native = match native {
+            Some(native) => native,
+            None => return Err(self.runtime_error(format!("Undefined property '{name}'."))),
+        };
+        // The receiver stays on the stack while the bound method is allocated.
+        let bound = self.alloc(BoundNative::new(*self.peek(0), native));
+        self.pop();
+        self.push(Value::Obj(Object::BoundNative(bound)));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> Result<(), InterpretError> {
+        if arg_count as usize != closure.function.arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                closure.function.arity, arg_count
+            )));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow.".to_string()));
         }
+        let slots = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame::new(closure, slots));
+        Ok(())
+    }
+
+    /// Call `native` with the arguments on top of the stack, preceded by the
+    /// value in the callee's slot if it is a method.
+    fn call_native(
+        &mut self,
+        native: Gc<Native>,
+        arg_count: u8,
+        method: bool,
+    ) -> Result<(), InterpretError> {
+        if arg_count as usize != native.arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            )));
+        }
+        let callee_slot = self.stack.len() - arg_count as usize - 1;
+        let args_start = if method { callee_slot } else { callee_slot + 1 };
+        let (heap, strings, roots) = self.split();
+        let mut context = NativeContext {
+            heap,
+            strings,
+            mark_roots: &|tracer| roots.trace(tracer),
+            handles: roots.handles,
+            objects: vec![],
+        };
+        match (native.function)(&mut context, &roots.stack[args_start..]) {
+            Ok(result) => {
+                self.stack.truncate(callee_slot);
+                self.push(result);
+                Ok(())
+            }
+            Err(message) => Err(self.runtime_error(message)),
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<RefCell<Upvalue>> {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match *upvalue.borrow() {
+                Upvalue::Open(open_slot) if open_slot == slot => return *upvalue,
+                Upvalue::Open(open_slot) if open_slot < slot => break,
+                _ => insert_at = i,
+            }
+        }
+        let upvalue = self.alloc(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Move every open upvalue at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => unreachable!("Closed upvalue in open list!"),
+            };
+            if slot < last {
+                break;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Allocate `object` on the heap, collecting garbage first if the heap
+    /// has grown enough. The new object is kept alive for the collection.
+    fn alloc<T: Trace + 'static>(&mut self, object: T) -> Gc<T> {
+        let object = self.heap.alloc(object);
+        if self.heap.should_collect() {
+            self.collect_garbage(|tracer| tracer.mark(object));
+        }
+        object
+    }
+
+    fn intern(&mut self, string: String) -> Gc<String> {
+        let string = self.strings.new_string(&mut self.heap, string);
+        if self.heap.should_collect() {
+            self.collect_garbage(|tracer| tracer.mark(string));
+        }
+        string
+    }
+
+    fn collect_garbage<F: FnOnce(&mut Tracer)>(&mut self, mark_extra: F) {
+        let (heap, strings, roots) = self.split();
+        heap.collect(strings, |tracer| {
+            roots.trace(tracer);
+            mark_extra(tracer);
+        });
+    }
+
+    /// Borrow the heap and interned strings apart from everything else the
+    /// VM holds, so they can be collected with it as the roots.
+    fn split(&mut self) -> (&mut Heap, &mut Strings, Roots<'_>) {
+        let roots = Roots {
+            stack: &self.stack,
+            frames: &self.frames,
+            open_upvalues: &self.open_upvalues,
+            globals: &self.globals,
+            list_methods: &self.list_methods,
+            string_methods: &self.string_methods,
+            map_methods: &self.map_methods,
+            init_string: self.init_string,
+            iter_string: self.iter_string,
+            next_string: self.next_string,
+            handles: &self.handles,
+        };
+        (&mut self.heap, &mut self.strings, roots)
     }
 
     fn push(&mut self, value: Value) {
-        if cfg!(feature = "trace") {
-            println!("Pushing {value}");
+        if self.trace {
+            writeln!(self.err_stream, "Pushing {value}").unwrap();
         }
-        self.current_frame_mut().slots.push(value);
+        self.stack.push(value);
     }
 
     fn pop(&mut self) -> Value {
-        match self.current_frame_mut().slots.pop() {
+        match self.stack.pop() {
             Some(x) => x,
             None => panic!("Tried to pop an empty stack!"),
         }
     }
 
     fn peek(&self, distance: usize) -> &Value {
-        &self.current_frame().slots[self.current_frame().slots.len() - 1 - distance]
+        &self.stack[self.stack.len() - 1 - distance]
     }
 
-    fn iter(&self) -> Iter<'_, Value> {
-        self.current_frame().slots.iter()
+    fn iter(&self) -> slice::Iter<'_, Value> {
+        self.stack.iter()
+    }
+
+    /// Build a runtime error from the current call stack and report it to
+    /// the error stream, showing the source line the innermost frame failed
+    /// on.
+    fn runtime_error(&mut self, message: String) -> InterpretError {
+        // Innermost frame first. Each frame's ip has already moved past the
+        // instruction that failed, or past the call it is waiting on.
+        let stack_trace: Vec<StackFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                let op_idx = function.chunk.get_op_idx(frame.ip - 1);
+                StackFrame {
+                    line: function.chunk.get_line_no(op_idx),
+                    column: function.chunk.get_column(op_idx).0,
+                    function: function.name.clone(),
+                }
+            })
+            .collect();
+
+        writeln!(self.err_stream, "{message}").unwrap();
+        if let Some(frame) = self.frames.last() {
+            let chunk = &frame.closure.function.chunk;
+            let op_idx = chunk.get_op_idx(frame.ip - 1);
+            let (column, width) = chunk.get_column(op_idx);
+            let line = chunk.get_line_no(op_idx);
+            let snippet = diagnostic::snippet(&chunk.source, line, column, width);
+            write!(self.err_stream, "{snippet}").unwrap();
+        }
+        for frame in &stack_trace {
+            writeln!(self.err_stream, "{frame}").unwrap();
+        }
+
+        InterpretError::RuntimeError {
+            message,
+            stack_trace,
+        }
     }
 
-    fn runtime_error(&mut self, mut message: String) -> InterpretError {
-        let line = self.current_frame().function.chunk.get_line_no(
-            self.current_frame()
-                .function
-                .chunk
-                .get_op_idx(cmp::max(self.current_frame().ip, 1) - 1),
-        );
-        writeln!(&mut message, "\n[line {line}] in script").unwrap();
-        InterpretError::RuntimeError(message)
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     fn current_frame(&self) -> &CallFrame {
@@ -247,15 +1178,115 @@ impl<'a, O: Write, E: Write> VM<'a, O, E> {
         self.frames.last_mut().unwrap()
     }
 
-    fn current_chunk(&mut self) -> &Chunk {
-        &self.current_frame().function.chunk
+    fn current_chunk(&self) -> &Chunk {
+        &self.current_frame().closure.function.chunk
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError(String),
+    CompileError(Vec<Diagnostic>),
+    RuntimeError {
+        message: String,
+        stack_trace: Vec<StackFrame>,
+    },
+    LoadError(LoadError),
+}
+
+/// Displays as the error message followed by its traceback, one frame per
+/// line.
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            InterpretError::RuntimeError {
+                message,
+                stack_trace,
+            } => {
+                writeln!(f, "{message}")?;
+                for frame in stack_trace {
+                    writeln!(f, "{frame}")?;
+                }
+                Ok(())
+            }
+            InterpretError::LoadError(err) => writeln!(f, "{err}"),
+        }
+    }
+}
+
+/// A call frame that was active when a runtime error occurred. `line` and
+/// `column` locate the instruction it was executing, and `function` is `None`
+/// for the top-level script.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackFrame {
+    pub line: u32,
+    pub column: usize,
+    pub function: Option<String>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+/// Everything a `Vm` holds that the collector treats as a root.
+struct Roots<'a> {
+    stack: &'a [Value],
+    frames: &'a [CallFrame],
+    open_upvalues: &'a [Gc<RefCell<Upvalue>>],
+    globals: &'a HashMap<Gc<String>, Value>,
+    list_methods: &'a HashMap<Gc<String>, Gc<Native>>,
+    string_methods: &'a HashMap<Gc<String>, Gc<Native>>,
+    map_methods: &'a HashMap<Gc<String>, Gc<Native>>,
+    init_string: Gc<String>,
+    iter_string: Gc<String>,
+    next_string: Gc<String>,
+    handles: &'a Handles,
+}
+
+impl Trace for Roots<'_> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.stack {
+            value.trace(tracer);
+        }
+        for frame in self.frames {
+            tracer.mark(frame.closure);
+        }
+        for upvalue in self.open_upvalues {
+            tracer.mark(*upvalue);
+        }
+        mark_globals(self.globals, tracer);
+        mark_methods(self.list_methods, tracer);
+        mark_methods(self.string_methods, tracer);
+        mark_methods(self.map_methods, tracer);
+        tracer.mark(self.init_string);
+        tracer.mark(self.iter_string);
+        tracer.mark(self.next_string);
+        self.handles.trace(tracer);
+    }
+}
+
+fn mark_globals(globals: &HashMap<Gc<String>, Value>, tracer: &mut Tracer) {
+    for (name, value) in globals {
+        tracer.mark(*name);
+        value.trace(tracer);
+    }
+}
+
+fn mark_methods(methods: &HashMap<Gc<String>, Gc<Native>>, tracer: &mut Tracer) {
+    for (name, native) in methods {
+        tracer.mark(*name);
+        tracer.mark(*native);
+    }
 }
 
 fn is_falsey(value: &Value) -> bool {
@@ -267,24 +1298,20 @@ fn is_falsey(value: &Value) -> bool {
 }
 
 fn values_equal(a: Value, b: Value) -> bool {
-    match a {
-        Value::Bool(_) => a == b,
-        Value::Nil => true,
-        Value::Number(_) => a == b,
-        Value::Obj(_) => a == b,
-    }
+    a == b
 }
 
 struct CallFrame {
-    function: Function,
+    closure: Gc<Closure>,
     ip: usize,
-    slots: Vec<Value>,
+    /// Index of the frame's first stack slot in `VM::stack`.
+    slots: usize,
 }
 
 impl CallFrame {
-    fn new(function: Function, slots: Vec<Value>) -> Self {
+    fn new(closure: Gc<Closure>, slots: usize) -> Self {
         CallFrame {
-            function,
+            closure,
             ip: 0,
             slots,
         }
@@ -0,0 +1,169 @@
+use rstest::*;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::{env, fs, str};
+
+/// Run the `lox` binary with `args`, feeding it `stdin`.
+fn lox(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    str::from_utf8(&output.stdout).unwrap()
+}
+
+fn stderr(output: &Output) -> &str {
+    str::from_utf8(&output.stderr).unwrap()
+}
+
+#[rstest]
+#[case::subcommand(&["run", "-"])]
+#[case::bare_path(&["-"])]
+fn runs_script_from_stdin(#[case] args: &[&str]) {
+    let output = lox(args, "print 1 + 2;");
+    assert_eq!(stdout(&output), "3\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[rstest]
+fn check_compiles_without_running() {
+    let output = lox(&["check", "-"], "print 1;");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "");
+
+    let output = lox(&["check", "-"], "print 1;\nprint ;");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "[line 2:7] Error: Expect expression\n    print ;\n          ^\n"
+    );
+}
+
+#[rstest]
+fn compiled_and_assembled_files_run() {
+    let dir = env::temp_dir().join(format!("lox-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("hello.lox");
+    fs::write(&source, "print 6 * 7;").unwrap();
+
+    // Output defaults to the input with a .loxc extension.
+    let output = lox(&["compile", source.to_str().unwrap()], "");
+    assert_eq!(stderr(&output), "");
+    let compiled = dir.join("hello.loxc");
+    assert_eq!(
+        stdout(&lox(&["run", compiled.to_str().unwrap()], "")),
+        "42\n"
+    );
+
+    let assembled = dir.join("hello-asm.loxc");
+    let text = stdout(&lox(&["disasm", source.to_str().unwrap()], "")).to_string();
+    let output = lox(&["asm", "-", "-o", assembled.to_str().unwrap()], &text);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&lox(&[assembled.to_str().unwrap()], "")), "42\n");
+    assert_eq!(
+        fs::read(&compiled).unwrap().len(),
+        fs::read(&assembled).unwrap().len()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[rstest]
+fn trace_goes_to_stderr() {
+    let output = lox(&["run", "--trace", "-"], "print 1;");
+    assert_eq!(stdout(&output), "1\n");
+    assert!(stderr(&output).contains("== <script> ==\n0000    1 OP_CONSTANT"));
+    assert!(stderr(&output).contains("0002    1 OP_PRINT\n          [ <script>][1]\n"));
+}
+
+#[rstest]
+#[case::unknown_option(&["check", "--trace", "x.lox"], "lox: unknown option '--trace' for check")]
+#[case::missing_file(&["disasm"], "lox: disasm needs a file")]
+#[case::extra_argument(&["run", "a.lox", "b.lox"], "lox: unexpected argument 'b.lox'")]
+#[case::missing_output(&["compile", "a.lox", "-o"], "lox: -o needs an output path")]
+fn bad_arguments_print_usage(#[case] args: &[&str], #[case] message: &str) {
+    let output = lox(args, "");
+    assert_eq!(output.status.code(), Some(64));
+    assert!(stderr(&output).starts_with(&format!("{message}\n\nUsage: lox")));
+}
+
+#[rstest]
+#[case::success(&["-"], "print 1;", Some(0))]
+#[case::compile_error(&["-"], "print ;", Some(65))]
+#[case::runtime_error(&["-"], "print -nil;", Some(70))]
+#[case::bad_bytecode(&["-"], "LOXC\u{2}\u{0}", Some(65))]
+#[case::check_error(&["check", "-"], "var;", Some(65))]
+#[case::missing_file(&["run", "no/such/file.lox"], "", Some(74))]
+fn exit_codes_follow_sysexits(
+    #[case] args: &[&str],
+    #[case] stdin: &str,
+    #[case] expected: Option<i32>,
+) {
+    let output = lox(args, stdin);
+    assert_eq!(output.status.code(), expected);
+    if expected != Some(0) {
+        assert_eq!(stdout(&output), "");
+        assert_ne!(stderr(&output), "");
+    }
+}
+
+#[rstest]
+fn read_errors_name_the_file() {
+    let output = lox(&["check", "no/such/file.lox"], "");
+    assert!(stderr(&output).starts_with("lox: no/such/file.lox: "));
+}
+
+#[rstest]
+fn repl_reads_stdin_until_eof() {
+    let output = lox(
+        &["repl"],
+        "var a = 1;\nfun f(x) {\n  return x + a;\n}\nf(2)\nprint ;\na * 10\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n10\n");
+    assert!(stderr(&output).starts_with("[line 1:7] Error: Expect expression"));
+}
+
+#[rstest]
+fn lox_scripts_meet_their_expectations() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/lox");
+    let output = lox(&["test", dir], "");
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+    assert!(stdout(&output).ends_with(" passed, 0 failed.\n"));
+}
+
+#[rstest]
+fn test_reports_failures() {
+    let dir = env::temp_dir().join(format!("lox-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.lox"), "print 1; // expect: 1\n").unwrap();
+    fs::write(dir.join("b.lox"), "print 1; // expect: 2\nprint -nil;\n").unwrap();
+    fs::write(dir.join("notes.txt"), "print 1;\n").unwrap();
+
+    let output = lox(&["test", dir.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "FAIL {}\n    Expected output '2' on line 1 and got '1'.\n    \
+             Unexpected runtime error 'Operand must be a number.'.\n1 passed, 1 failed.\n",
+            dir.join("b.lox").display()
+        )
+    );
+    assert_eq!(stderr(&output), "");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,166 @@
+use lox::repl::Repl;
+use lox::vm::{InterpretError, Vm};
+use rstest::*;
+use std::io::Cursor;
+use std::{env, fs, str};
+
+fn new_repl() -> Repl<Cursor<Vec<u8>>, Cursor<Vec<u8>>> {
+    Repl::new(Vm::new(Cursor::new(Vec::new()), Cursor::new(Vec::new())))
+}
+
+fn output(repl: Repl<Cursor<Vec<u8>>, Cursor<Vec<u8>>>) -> String {
+    streams(repl).0
+}
+
+/// What the REPL wrote to its output and error streams.
+fn streams(repl: Repl<Cursor<Vec<u8>>, Cursor<Vec<u8>>>) -> (String, String) {
+    let (out_stream, err_stream) = repl.into_vm().into_streams();
+    (
+        str::from_utf8(out_stream.get_ref()).unwrap().to_string(),
+        str::from_utf8(err_stream.get_ref()).unwrap().to_string(),
+    )
+}
+
+#[rstest]
+fn multi_line_input_waits_for_completion() {
+    let mut repl = new_repl();
+    assert_eq!(repl.prompt(), "> ");
+    assert_eq!(repl.push_line("fun add(a, b) {"), None);
+    assert_eq!(repl.prompt(), "... ");
+    assert_eq!(repl.push_line("  return a + b;"), None);
+    assert_eq!(repl.push_line("}"), Some(Ok(())));
+    assert_eq!(repl.prompt(), "> ");
+    assert_eq!(repl.push_line("var s = \"multi"), None);
+    assert_eq!(repl.push_line("line\";"), Some(Ok(())));
+    assert_eq!(repl.push_line("print add(1, 2);"), Some(Ok(())));
+    assert_eq!(output(repl), "3\n");
+}
+
+#[rstest]
+#[case::expression("1 + 2", "3\n")]
+#[case::with_semicolon("1 + 2;", "3\n")]
+#[case::several("var a = 1; a; a = 2; print a + 1;", "1\n2\n3\n")]
+#[case::not_in_blocks("{ 1; }", "")]
+#[case::not_in_functions("fun f() { 1; } f();", "nil\n")]
+fn bare_expressions_are_printed(#[case] line: &str, #[case] expected: &str) {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line(line), Some(Ok(())));
+    assert_eq!(output(repl), expected);
+}
+
+#[rstest]
+fn errors_do_not_end_the_session() {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line("var a = 1;"), Some(Ok(())));
+    assert!(matches!(
+        repl.push_line("a +"),
+        Some(Err(InterpretError::CompileError(_)))
+    ));
+    assert!(matches!(
+        repl.push_line("a()"),
+        Some(Err(InterpretError::RuntimeError { .. }))
+    ));
+    assert_eq!(repl.push_line("a"), Some(Ok(())));
+    assert_eq!(output(repl), "1\n");
+}
+
+#[rstest]
+fn blank_line_or_cancel_abandons_incomplete_input() {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line("if (true) {"), None);
+    assert!(matches!(
+        repl.push_line(""),
+        Some(Err(InterpretError::CompileError(_)))
+    ));
+    assert_eq!(repl.push_line("print (1 +"), None);
+    repl.cancel();
+    assert_eq!(repl.prompt(), "> ");
+    assert_eq!(repl.push_line("2"), Some(Ok(())));
+    assert_eq!(output(repl), "2\n");
+}
+
+#[rstest]
+fn globals_lists_globals_until_reset() {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line("var b = \"two\"; var a = 1;"), Some(Ok(())));
+    assert_eq!(repl.push_line(":globals"), Some(Ok(())));
+    assert_eq!(repl.push_line(":reset"), Some(Ok(())));
+    assert_eq!(repl.push_line(":globals"), Some(Ok(())));
+    assert!(matches!(
+        repl.push_line("a"),
+        Some(Err(InterpretError::RuntimeError { .. }))
+    ));
+    let output = output(repl);
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[0], "a = 1");
+    assert_eq!(lines[1], "b = \"two\"");
+    assert!(lines[2].starts_with("clock = "));
+    assert_eq!(lines.len(), 2 + 2 * 5);
+    assert!(lines[7].starts_with("clock = "));
+}
+
+#[rstest]
+#[case::expression(
+    ":dis 1 + 2",
+    "    OP_CONSTANT 1\n    OP_CONSTANT 2\n    OP_ADD\n    OP_POP\n"
+)]
+#[case::statement(":dis print nil;", "    OP_NIL\n    OP_PRINT\n")]
+#[case::ends_in_map(
+    ":dis m = {\"a\": nil}",
+    "    OP_BUILD_MAP 1\n    OP_SET_GLOBAL m\n    OP_POP\n"
+)]
+#[case::block(":dis { print nil; }", "    OP_NIL\n    OP_PRINT\n    OP_NIL\n    OP_RETURN\n")]
+fn dis_shows_bytecode(#[case] line: &str, #[case] expected: &str) {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line(line), Some(Ok(())));
+    let output = output(repl);
+    assert!(output.contains(expected), "{}", output);
+}
+
+#[rstest]
+fn load_runs_a_file_in_the_session() {
+    let path = env::temp_dir().join(format!("lox-repl-{}.lox", std::process::id()));
+    fs::write(&path, "var a = 20;\nprint \"loaded\";\n").unwrap();
+    let mut repl = new_repl();
+    let command = format!(":load {}", path.display());
+    assert_eq!(repl.push_line(&command), Some(Ok(())));
+    assert_eq!(repl.push_line("a + 1"), Some(Ok(())));
+    fs::remove_file(&path).unwrap();
+    assert_eq!(repl.push_line(&command), Some(Ok(())));
+    let (output, errors) = streams(repl);
+    assert_eq!(output, "\"loaded\"\n21\n");
+    assert!(errors.starts_with(&format!("{}: ", path.display())));
+}
+
+#[rstest]
+fn time_and_trace_write_to_the_error_stream() {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line(":time"), Some(Ok(())));
+    assert_eq!(repl.push_line("1"), Some(Ok(())));
+    assert_eq!(repl.push_line(":time"), Some(Ok(())));
+    assert_eq!(repl.push_line(":trace on"), Some(Ok(())));
+    assert_eq!(repl.push_line("2"), Some(Ok(())));
+    assert_eq!(repl.push_line(":trace off"), Some(Ok(())));
+    assert_eq!(repl.push_line("3"), Some(Ok(())));
+    let (output, errors) = streams(repl);
+    assert_eq!(output, "Timing is on.\n1\nTiming is off.\n2\n3\n");
+    let lines: Vec<&str> = errors.lines().collect();
+    assert!(lines[0].starts_with('[') && lines[0].ends_with(']'));
+    assert_eq!(lines[1], "== <script> ==");
+    assert!(!errors.contains("'3'"));
+}
+
+#[rstest]
+#[case::unknown(":frobnicate")]
+#[case::missing_argument(":load")]
+#[case::bad_argument(":trace maybe")]
+fn bad_commands_are_reported(#[case] line: &str) {
+    let mut repl = new_repl();
+    assert_eq!(repl.push_line(line), Some(Ok(())));
+    let (output, errors) = streams(repl);
+    assert_eq!(output, "");
+    assert_eq!(
+        errors,
+        format!("Unknown command '{line}'. Type :help for a list.\n")
+    );
+}
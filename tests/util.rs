@@ -1,5 +1,7 @@
-use lox::vm::{interpret, InterpretError};
+use lox::vm::{interpret, InterpretError, StackFrame, Vm};
+use lox::Diagnostic;
 use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::str;
 
 pub fn assert_interpreter_output(
@@ -18,6 +20,75 @@ pub fn assert_interpreter_output(
 
     assert_eq!(read_cursor(out_cursor), expected_output);
     assert_eq!(read_cursor(err_cursor), expected_error);
+
+    // Programs that compile should behave the same when loaded as bytecode,
+    // which also puts the compiler's output through the verifier.
+    if !matches!(expected_result, Err(InterpretError::CompileError(_))) {
+        let mut vm = Vm::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()));
+        let bytes = vm.compile(input).unwrap();
+        let result = vm.eval_bytecode(&mut bytes.as_slice());
+        let (out_cursor, _) = vm.into_streams();
+        assert_eq!(result, expected_result);
+        assert_eq!(read_cursor(out_cursor), expected_output);
+
+        // And when disassembled and assembled again, though columns are lost.
+        let mut vm = Vm::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()));
+        let text = vm.disassemble(input).unwrap();
+        let result = vm.eval_assembly(&text);
+        let (out_cursor, _) = vm.into_streams();
+        assert_eq!(without_columns(result), without_columns(expected_result));
+        assert_eq!(read_cursor(out_cursor), expected_output);
+    }
+}
+
+fn without_columns(result: Result<(), InterpretError>) -> Result<(), InterpretError> {
+    match result {
+        Err(InterpretError::RuntimeError {
+            message,
+            stack_trace,
+        }) => Err(InterpretError::RuntimeError {
+            message,
+            stack_trace: stack_trace
+                .into_iter()
+                .map(|frame| StackFrame { column: 0, ..frame })
+                .collect(),
+        }),
+        result => result,
+    }
+}
+
+/// Build the expected result of a failed compile from the
+/// `(line, column, span, message)` of each error reported.
+pub fn compile_error(
+    diagnostics: &[(u32, usize, Range<usize>, &str)],
+) -> Result<(), InterpretError> {
+    Err(InterpretError::CompileError(
+        diagnostics
+            .iter()
+            .map(|(line, column, span, message)| {
+                Diagnostic::error(*line, *column, span.clone(), message.to_string())
+            })
+            .collect(),
+    ))
+}
+
+/// Build the expected result of a runtime error from its message and the
+/// `(line, column, function)` of each frame, innermost first.
+pub fn runtime_error(
+    message: &str,
+    stack_trace: &[(u32, usize, Option<&str>)],
+) -> Result<(), InterpretError> {
+    Err(InterpretError::RuntimeError {
+        message: message.to_string(),
+        stack_trace: stack_trace
+            .iter()
+            .map(|&(line, column, function)| StackFrame {
+                line,
+                column,
+                function: function.map(str::to_string),
+            })
+            .collect(),
+    })
 }
 
 fn read_cursor(mut cursor: Cursor<Vec<u8>>) -> String {
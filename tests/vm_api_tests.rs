@@ -0,0 +1,437 @@
+use lox::vm::{InterpretError, StackFrame, Vm};
+use lox::{Diagnostic, Handle, LoadError, NativeContext, VerifyError, VerifyErrorKind};
+use std::cell::RefCell;
+use rstest::*;
+use std::io::Cursor;
+use std::str;
+
+fn output(vm: Vm<Cursor<Vec<u8>>, Cursor<Vec<u8>>>) -> String {
+    let (out_stream, _) = vm.into_streams();
+    str::from_utf8(out_stream.get_ref()).unwrap().to_string()
+}
+
+fn new_vm() -> Vm<Cursor<Vec<u8>>, Cursor<Vec<u8>>> {
+    Vm::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()))
+}
+
+#[rstest]
+fn globals_persist_across_eval() {
+    let mut vm = new_vm();
+    assert_eq!(vm.eval("var a = 1;"), Ok(()));
+    assert_eq!(vm.eval("fun f() { return a + 1; }"), Ok(()));
+    assert_eq!(vm.eval("print f();"), Ok(()));
+    assert_eq!(output(vm), "2\n");
+}
+
+#[rstest]
+fn vm_is_usable_after_errors() {
+    let mut vm = new_vm();
+    assert_eq!(vm.eval("var a = 1;"), Ok(()));
+    assert_eq!(
+        vm.eval("print a +;"),
+        Err(InterpretError::CompileError(vec![Diagnostic::error(
+            1,
+            10,
+            9..10,
+            "Expect expression".to_string()
+        )]))
+    );
+    assert_eq!(
+        vm.eval("fun f() { return nil + 1; } f();"),
+        Err(InterpretError::RuntimeError {
+            message: "Operands must be numbers.".to_string(),
+            stack_trace: vec![
+                StackFrame {
+                    line: 1,
+                    column: 22,
+                    function: Some("f".to_string()),
+                },
+                StackFrame {
+                    line: 1,
+                    column: 30,
+                    function: None,
+                },
+            ],
+        })
+    );
+    assert_eq!(vm.eval("print a;"), Ok(()));
+    assert_eq!(output(vm), "1\n");
+}
+
+#[rstest]
+fn get_and_set_globals() {
+    let mut vm = new_vm();
+    assert_eq!(vm.get_global("a"), None);
+    vm.set_global("a", &Handle::from(2.0));
+    assert_eq!(vm.eval("var b = a * 3;"), Ok(()));
+    assert_eq!(vm.get_global("b"), Some(Handle::from(6.0)));
+
+    let greeting = vm.new_string("hi".to_string());
+    assert_eq!(greeting.string(), Some("hi".to_string()));
+    vm.set_global("greeting", &greeting);
+    assert_eq!(vm.eval("print greeting + \"!\";"), Ok(()));
+    assert_eq!(output(vm), "\"hi!\"\n");
+}
+
+#[rstest]
+fn values_handed_out_survive_collection() {
+    let mut vm = new_vm();
+    let greeting = vm.new_string("hi".to_string());
+    assert_eq!(vm.eval("var xs = [1, 2]; fun pair() { return [3, 4]; }"), Ok(()));
+    let xs = vm.get_global("xs").unwrap();
+    let pair = vm.call_function("pair", &[]).unwrap();
+
+    // Drop the only Lox reference to the list and allocate enough to collect.
+    let churn = "xs = nil; for (var i = 0; i < 100000; i = i + 1) { var l = [i]; }";
+    assert_eq!(vm.eval(churn), Ok(()));
+
+    vm.set_global("greeting", &greeting);
+    vm.set_global("xs", &xs);
+    vm.set_global("pair", &pair);
+    assert_eq!(vm.eval("print greeting; print xs; print pair;"), Ok(()));
+    assert_eq!(output(vm), "\"hi\"\n[1, 2]\n[3, 4]\n");
+}
+
+#[rstest]
+fn call_function_returns_result() {
+    let mut vm = new_vm();
+    assert_eq!(vm.eval("fun add(a, b) { return a + b; }"), Ok(()));
+    assert_eq!(
+        vm.call_function("add", &[Handle::from(1.0), Handle::from(2.0)]),
+        Ok(Handle::from(3.0))
+    );
+    assert_eq!(
+        vm.call_function("add", &[Handle::from(1.0)]),
+        Err(InterpretError::RuntimeError {
+            message: "Expected 2 arguments but got 1.".to_string(),
+            stack_trace: vec![],
+        })
+    );
+    assert_eq!(
+        vm.call_function("missing", &[]),
+        Err(InterpretError::RuntimeError {
+            message: "Undefined variable 'missing'.".to_string(),
+            stack_trace: vec![],
+        })
+    );
+}
+
+#[rstest]
+fn define_native_is_callable() {
+    fn double(_: &mut NativeContext, args: &[Handle]) -> Result<Handle, String> {
+        match args[0].as_number() {
+            Some(n) => Ok(Handle::from(n * 2.0)),
+            None => Err("double() expects a number.".to_string()),
+        }
+    }
+
+    let mut vm = new_vm();
+    vm.define_native("double", 1, double);
+    assert_eq!(vm.eval("print double(21);"), Ok(()));
+    assert_eq!(
+        vm.call_function("double", &[Handle::from(2.0)]),
+        Ok(Handle::from(4.0))
+    );
+    assert_eq!(output(vm), "42\n");
+}
+
+#[rstest]
+fn natives_can_make_objects() {
+    fn greet(context: &mut NativeContext, args: &[Handle]) -> Result<Handle, String> {
+        let name = args[0].string().ok_or("greet() expects a string.")?;
+        let greeting = context.new_string(format!("hi {name}"));
+        Ok(context.new_list(&[greeting, args[0].clone()]))
+    }
+
+    let mut vm = new_vm();
+    vm.define_native("greet", 1, greet);
+    assert_eq!(vm.eval("print greet(\"bo\");"), Ok(()));
+    assert_eq!(output(vm), "[\"hi bo\", \"bo\"]\n");
+}
+
+#[rstest]
+fn objects_natives_make_survive_collection() {
+    fn words(context: &mut NativeContext, args: &[Handle]) -> Result<Handle, String> {
+        let count = args[0].as_number().ok_or("words() expects a number.")?;
+        let words: Vec<Handle> = (0..count as usize)
+            .map(|i| context.new_string(format!("word {i}")))
+            .collect();
+        Ok(context.new_list(&words))
+    }
+
+    let mut vm = new_vm();
+    vm.define_native("words", 1, words);
+    // Enough words that making them collects garbage.
+    let source = "var ws = words(50000); print ws.len(); print ws[0] + ws[49999];";
+    assert_eq!(vm.eval(source), Ok(()));
+    assert_eq!(output(vm), "50000\n\"word 0word 49999\"\n");
+}
+
+#[rstest]
+fn natives_can_keep_handles() {
+    let kept = RefCell::new(Handle::nil());
+    let mut vm = new_vm();
+    vm.define_native("keep", 1, move |_, args| {
+        Ok(kept.replace(args[0].clone()))
+    });
+    assert_eq!(vm.eval("keep([1, 2]);"), Ok(()));
+    let churn = "for (var i = 0; i < 100000; i = i + 1) { var l = [i]; }";
+    assert_eq!(vm.eval(churn), Ok(()));
+    assert_eq!(vm.eval("print keep(nil);"), Ok(()));
+    assert_eq!(output(vm), "[1, 2]\n");
+}
+
+#[rstest]
+#[should_panic(expected = "Handle used after its Vm was dropped.")]
+fn handles_outliving_their_vm_panic() {
+    let mut vm = new_vm();
+    let greeting = vm.new_string("hi".to_string());
+    drop(vm);
+    greeting.to_string();
+}
+
+#[rstest]
+#[should_panic(expected = "Handle passed to a Vm that didn't make it.")]
+fn handles_from_another_vm_panic() {
+    let greeting = new_vm().new_string("hi".to_string());
+    new_vm().set_global("greeting", &greeting);
+}
+
+#[rstest]
+fn runtime_error_displays_traceback() {
+    let mut vm = new_vm();
+    let err = vm
+        .eval("fun f() {\n  return nil + 1;\n}\nf();")
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Operands must be numbers.\n[line 2] in f()\n[line 4] in script\n"
+    );
+}
+
+#[rstest]
+fn compiled_bytecode_runs() {
+    let source = "
+class Counter {
+  init(start) { this.count = start; }
+  next() {
+    this.count = this.count + 1;
+    return this.count;
+  }
+}
+class Loud < Counter {
+  next() { return super.next() * 2; }
+}
+fun twice(f) {
+  fun run() { f(); return f(); }
+  return run;
+}
+var c = Loud(1.5);
+print twice(c.next)();
+print nil == false;
+";
+    let bytes = new_vm().compile(source).unwrap();
+    assert!(bytes.starts_with(b"LOXC"));
+
+    let mut vm = new_vm();
+    assert_eq!(vm.eval_bytecode(&mut bytes.as_slice()), Ok(()));
+    assert_eq!(output(vm), "7\nfalse\n");
+}
+
+#[rstest]
+fn bytecode_runtime_errors_have_stack_traces() {
+    let bytes = new_vm()
+        .compile("fun f() {\n  return -nil;\n}\nf();")
+        .unwrap();
+    let mut vm = new_vm();
+    assert_eq!(
+        vm.eval_bytecode(&mut bytes.as_slice()),
+        Err(InterpretError::RuntimeError {
+            message: "Operand must be a number.".to_string(),
+            stack_trace: vec![
+                StackFrame {
+                    line: 2,
+                    column: 10,
+                    function: Some("f".to_string()),
+                },
+                StackFrame {
+                    line: 4,
+                    column: 2,
+                    function: None,
+                },
+            ],
+        })
+    );
+}
+
+#[rstest]
+#[case::empty(b"", LoadError::Malformed("unexpected end of file".to_string()))]
+#[case::bad_magic(b"LOX!\x01\x00", LoadError::BadMagic)]
+#[case::future_version(b"LOXC\x63\x00", LoadError::UnsupportedVersion(99))]
+#[case::truncated(b"LOXC\x01\x00\x05\x00\x00\x00\x00", LoadError::Malformed("unexpected end of file".to_string()))]
+fn eval_bytecode_rejects_bad_input(#[case] bytes: &[u8], #[case] expected: LoadError) {
+    let mut vm = new_vm();
+    assert_eq!(
+        vm.eval_bytecode(&mut &bytes[..]),
+        Err(InterpretError::LoadError(expected))
+    );
+}
+
+#[rstest]
+fn eval_bytecode_rejects_deeply_nested_functions() {
+    let mut bytes = b"LOXC\x01\x00".to_vec();
+    for _ in 0..100_000 {
+        // No code, and one constant: a function whose chunk comes next.
+        bytes.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+    let mut vm = new_vm();
+    let expected = LoadError::Malformed("functions nested too deeply".to_string());
+    assert_eq!(
+        vm.eval_bytecode(&mut bytes.as_slice()),
+        Err(InterpretError::LoadError(expected))
+    );
+}
+
+#[rstest]
+fn eval_bytecode_verifies_code() {
+    let mut bytes = new_vm().compile("print 1;").unwrap();
+    // The first opcode follows the 6 byte header and the code length.
+    bytes[10] = 0xFF;
+    let mut vm = new_vm();
+    assert_eq!(
+        vm.eval_bytecode(&mut bytes.as_slice()),
+        Err(InterpretError::LoadError(LoadError::Invalid(vec![
+            VerifyError {
+                function: None,
+                offset: 0,
+                kind: VerifyErrorKind::InvalidOpCode(0xFF),
+            }
+        ])))
+    );
+}
+
+#[rstest]
+fn assembly_runs() {
+    let text = "
+    .line 1
+        OP_CONSTANT 0
+        OP_DEFINE_GLOBAL i
+    top:                        ; while (i < 3)
+        OP_GET_GLOBAL i
+        OP_CONSTANT 3
+        OP_LESS
+        OP_JUMP_IF_FALSE done
+        OP_POP
+    .line 2
+        OP_GET_GLOBAL i         ; print i; i = i + 1;
+        OP_PRINT
+        OP_GET_GLOBAL i
+        OP_CONSTANT 1
+        OP_ADD
+        OP_SET_GLOBAL i
+        OP_POP
+        OP_LOOP top
+    done:
+        OP_POP
+        OP_NIL
+        OP_RETURN
+    ";
+    let mut vm = new_vm();
+    assert_eq!(vm.eval_assembly(text), Ok(()));
+    assert_eq!(output(vm), "0\n1\n2\n");
+
+    let bytes = new_vm().assemble(text).unwrap();
+    let mut vm = new_vm();
+    assert_eq!(vm.eval_bytecode(&mut bytes.as_slice()), Ok(()));
+    assert_eq!(output(vm), "0\n1\n2\n");
+}
+
+#[rstest]
+fn disassembly_can_be_edited() {
+    let mut vm = new_vm();
+    let text = vm.disassemble("print 1 + 2;").unwrap();
+    assert_eq!(
+        text,
+        "    .line 1\n    OP_CONSTANT 1\n    OP_CONSTANT 2\n    OP_ADD\n    OP_PRINT\n    OP_NIL\n    OP_RETURN\n"
+    );
+    assert_eq!(
+        vm.eval_assembly(&text.replace("OP_ADD", "OP_SUBTRACT")),
+        Ok(())
+    );
+    assert_eq!(output(vm), "-1\n");
+}
+
+#[rstest]
+fn assembly_errors_are_reported() {
+    let mut vm = new_vm();
+    assert_eq!(
+        vm.eval_assembly("OP_NIL\nOP_PUSH 1\nOP_RETURN"),
+        Err(InterpretError::CompileError(vec![Diagnostic::error(
+            2,
+            1,
+            7..14,
+            "Unknown instruction 'OP_PUSH'.".to_string()
+        )]))
+    );
+    assert_eq!(
+        vm.eval_assembly("OP_ADD\nOP_RETURN"),
+        Err(InterpretError::LoadError(LoadError::Invalid(vec![
+            VerifyError {
+                function: None,
+                offset: 0,
+                kind: VerifyErrorKind::StackUnderflow,
+            }
+        ])))
+    );
+    let (_, err_stream) = vm.into_streams();
+    assert_eq!(
+        str::from_utf8(err_stream.get_ref()).unwrap(),
+        "[line 2:1] Error: Unknown instruction 'OP_PUSH'.\n    OP_PUSH 1\n    ^~~~~~~\n\
+         Invalid bytecode:\n    script at 0: stack underflow\n"
+    );
+}
+
+#[rstest]
+#[case::inherit_into_non_class("OP_CLASS A\nOP_NIL\nOP_INHERIT", "Only classes can inherit.")]
+#[case::method_on_non_class(
+    "OP_NIL\nOP_CLOSURE m 0 [] {\n OP_NIL\n OP_RETURN\n}\nOP_METHOD m",
+    "Only classes can have methods."
+)]
+#[case::method_not_closure("OP_CLASS A\nOP_NIL\nOP_METHOD m", "Only closures can be methods.")]
+#[case::super_not_class("OP_NIL\nOP_NIL\nOP_GET_SUPER m", "Superclass must be a class.")]
+#[case::step_non_iterator(
+    "OP_NIL\nOP_FOR_ITER done\nOP_POP\ndone:",
+    "Only iterators can be stepped."
+)]
+fn verified_code_with_wrong_types_is_a_runtime_error(#[case] text: &str, #[case] message: &str) {
+    let mut vm = new_vm();
+    let text = format!(".line 1\n{text}\nOP_NIL\nOP_RETURN");
+    assert_eq!(
+        vm.eval_assembly(&text),
+        Err(InterpretError::RuntimeError {
+            message: message.to_string(),
+            stack_trace: vec![StackFrame {
+                line: 1,
+                column: 0,
+                function: None,
+            }],
+        })
+    );
+}
+
+#[rstest]
+fn dump_json_includes_source_positions() {
+    let mut vm = new_vm();
+    assert_eq!(
+        vm.dump_json("print -x;").unwrap(),
+        concat!(
+            r#"{"name":null,"arity":0,"upvalue_count":0,"instructions":["#,
+            r#"{"offset":0,"size":2,"op":"OP_GET_GLOBAL","line":1,"column":8,"constant":0},"#,
+            r#"{"offset":2,"size":1,"op":"OP_NEGATE","line":1,"column":7},"#,
+            r#"{"offset":3,"size":1,"op":"OP_PRINT","line":1,"column":9},"#,
+            r#"{"offset":4,"size":1,"op":"OP_NIL","line":1,"column":10},"#,
+            r#"{"offset":5,"size":1,"op":"OP_RETURN","line":1,"column":10}],"#,
+            r#""constants":[{"type":"string","value":"x"}]}"#,
+        )
+    );
+}
@@ -1,7 +1,7 @@
 mod util;
 use lox::vm::InterpretError;
 use rstest::*;
-use util::assert_interpreter_output;
+use util::{assert_interpreter_output, compile_error, runtime_error};
 
 const RETURN_FALSE: &str = "false\n";
 const RETURN_TRUE: &str = "true\n";
@@ -13,12 +13,15 @@ const RETURN_TRUE: &str = "true\n";
 #[case::equal_false("print 1 == 2;", RETURN_FALSE, "", Result::Ok(()))]
 #[case::equal_true("print 1 == 1;", RETURN_TRUE, "", Result::Ok(()))]
 #[case::string_eq("print \"asdf\n\" == \"asdf\n\";", RETURN_TRUE, "", Result::Ok(()))]
+#[case::nil_neq("print nil != 1;", RETURN_TRUE, "", Result::Ok(()))]
+#[case::nil_eq("print nil == nil;", RETURN_TRUE, "", Result::Ok(()))]
 #[case::string_neq("print \"xyz\" == \"yzx\";", RETURN_FALSE, "", Result::Ok(()))]
 #[case::string_concat("print \"a\" + \"b\" == \"ab\";", RETURN_TRUE, "", Result::Ok(()))]
 #[case::global("var GLOB = 1; print GLOB;", "1\n", "", Result::Ok(()))]
 #[case::global_default("var GLOB; print GLOB;", "nil\n", "", Result::Ok(()))]
-#[case::global_uninit("print UNINIT;", "", "", Result::Err(InterpretError::RuntimeError(
-    "Undefined variable 'UNINIT'.\n[line 1] in script\n".to_string())))]
+#[case::global_uninit("print UNINIT;", "",
+    "Undefined variable 'UNINIT'.\n    print UNINIT;\n          ^~~~~~\n[line 1] in script\n",
+    runtime_error("Undefined variable 'UNINIT'.", &[(1, 7, None)]))]
 #[case::global_default(
 "var A = 3;\
 var B = 5;\
@@ -36,8 +39,8 @@ print A;", "8\n", "", Result::Ok(()))]
   var a = a + 3;\
 }",
     "",
-    "",
-    Result::Err(InterpretError::CompileError)
+    "[line 2:11] Error: Can't read local variable in its own initializer.\n      var a = a + 3;}\n              ^\n",
+    compile_error(&[(2, 11, 23..24, "Can't read local variable in its own initializer.")])
 )]
 #[case::if_(
 "if (true)
@@ -100,6 +103,432 @@ while (a < 2)\
 "for (var i = 0; i <= 2; i = i + 1)
   print i;
 ", "0\n1\n2\n", "", Result::Ok(()))]
+#[case::function(
+"fun add(a, b) {
+  return a + b;
+}
+print add(1, 2);
+", "3\n", "", Result::Ok(()))]
+#[case::recursion(
+"fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+print fib(10);
+", "55\n", "", Result::Ok(()))]
+#[case::implicit_return("fun f() {} print f();", "nil\n", "", Result::Ok(()))]
+#[case::local_function(
+"{
+  var a = 1;
+  fun f(b) {
+    var c = 2;
+    return b + c;
+  }
+  print f(a);
+}", "3\n", "", Result::Ok(()))]
+#[case::arity(
+"fun f(a) {}
+f(1, 2);", "",
+    "Expected 1 arguments but got 2.\n    f(1, 2);\n     ^\n[line 2] in script\n",
+    runtime_error("Expected 1 arguments but got 2.", &[(2, 2, None)]))]
+#[case::call_non_function("var a = 1; a();", "",
+    "Can only call functions and classes.\n    var a = 1; a();\n                ^\n[line 1] in script\n",
+    runtime_error("Can only call functions and classes.", &[(1, 13, None)]))]
+#[case::top_level_return("return 1;", "",
+    "[line 1:1] Error: Can't return from top-level code.\n    return 1;\n    ^~~~~~\n",
+    compile_error(&[(1, 1, 0..6, "Can't return from top-level code.")]))]
+#[case::closure_counter(
+"fun makeCounter() {
+  var i = 0;
+  fun count() {
+    i = i + 1;
+    return i;
+  }
+  return count;
+}
+var a = makeCounter();
+var b = makeCounter();
+print a();
+print a();
+print b();
+", "1\n2\n1\n", "", Result::Ok(()))]
+#[case::closure_nested(
+"fun outer() {
+  var x = 1;
+  fun middle() {
+    fun inner() {
+      return x;
+    }
+    return inner;
+  }
+  return middle;
+}
+print outer()()();
+", "1\n", "", Result::Ok(()))]
+#[case::closure_shared(
+"var set;
+var get;
+fun main() {
+  var a = 1;
+  fun s() { a = 2; }
+  fun g() { return a; }
+  set = s;
+  get = g;
+}
+main();
+set();
+print get();
+", "2\n", "", Result::Ok(()))]
+#[case::closure_block_scope(
+"var f;
+{
+  var a = 1;
+  fun g() { return a; }
+  f = g;
+  a = 2;
+}
+print f();
+", "2\n", "", Result::Ok(()))]
+#[case::class_fields(
+"class Pair {}
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+print pair.first + pair.second;
+print pair;
+", "3\nPair instance\n", "", Result::Ok(()))]
+#[case::class_methods(
+"class Point {
+  init(x, y) {
+    this.x = x;
+    this.y = y;
+  }
+  sum() {
+    return this.x + this.y;
+  }
+}
+var p = Point(1, 2);
+print p.sum();
+var sum = p.sum;
+p.x = 10;
+print sum();
+", "3\n12\n", "", Result::Ok(()))]
+#[case::this_in_closure(
+"class Thing {
+  getCallback() {
+    fun localFunction() {
+      return this;
+    }
+    return localFunction;
+  }
+}
+print Thing().getCallback()();
+", "Thing instance\n", "", Result::Ok(()))]
+#[case::init_returns_instance(
+"class A { init() { this.a = 1; return; } }
+print A().init().a;
+", "1\n", "", Result::Ok(()))]
+#[case::init_arity("class A {} A(1);", "",
+    "Expected 0 arguments but got 1.\n    class A {} A(1);\n                ^\n[line 1] in script\n",
+    runtime_error("Expected 0 arguments but got 1.", &[(1, 13, None)]))]
+#[case::undefined_property("class A {} print A().b;", "",
+    "Undefined property 'b'.\n    class A {} print A().b;\n                         ^\n[line 1] in script\n",
+    runtime_error("Undefined property 'b'.", &[(1, 22, None)]))]
+#[case::property_on_non_instance("var a = 1; print a.b;", "",
+    "Only instances have properties.\n    var a = 1; print a.b;\n                       ^\n[line 1] in script\n",
+    runtime_error("Only instances have properties.", &[(1, 20, None)]))]
+#[case::this_outside_class("print this;", "",
+    "[line 1:7] Error: Can't use 'this' outside of a class.\n    print this;\n          ^~~~\n",
+    compile_error(&[(1, 7, 6..10, "Can't use 'this' outside of a class.")]))]
+#[case::init_return_value(
+    "class A { init() { return 1; } }",
+    "",
+    "[line 1:20] Error: Can't return a value from an initializer.\n    class A { init() { return 1; } }\n                       ^~~~~~\n",
+    compile_error(&[(1, 20, 19..25, "Can't return a value from an initializer.")])
+)]
+#[case::inheritance(
+"class A {
+  method() { return \"A\"; }
+  other() { return \"other\"; }
+}
+class B < A {
+  method() { return \"B\"; }
+}
+var b = B();
+print b.method();
+print b.other();
+", "\"B\"\n\"other\"\n", "", Result::Ok(()))]
+#[case::super_call(
+"class A {
+  init(n) { this.n = n; }
+  method() { return this.n; }
+}
+class B < A {
+  init(n) { super.init(n * 2); }
+  method() { return super.method() + 1; }
+  closure() {
+    fun f() { return super.method(); }
+    return f;
+  }
+}
+var b = B(1);
+print b.method();
+print b.closure()();
+", "3\n2\n", "", Result::Ok(()))]
+#[case::inherit_non_class("var A = 1; class B < A {}", "",
+    "Superclass must be a class.\n    var A = 1; class B < A {}\n                         ^\n[line 1] in script\n",
+    runtime_error("Superclass must be a class.", &[(1, 22, None)]))]
+#[case::inherit_self("class A < A {}", "",
+    "[line 1:11] Error: A class can't inherit from itself.\n    class A < A {}\n              ^\n",
+    compile_error(&[(1, 11, 10..11, "A class can't inherit from itself.")]))]
+#[case::super_outside_class("super.a();", "",
+    "[line 1:1] Error: Can't use 'super' outside of a class.\n    super.a();\n    ^~~~~\n",
+    compile_error(&[(1, 1, 0..5, "Can't use 'super' outside of a class.")]))]
+#[case::super_without_superclass(
+    "class A { f() { super.f(); } }",
+    "",
+    "[line 1:17] Error: Can't use 'super' in a class with no superclass.\n    class A { f() { super.f(); } }\n                    ^~~~~\n",
+    compile_error(&[(1, 17, 16..21, "Can't use 'super' in a class with no superclass.")])
+)]
+#[case::cyclic_instances(
+"class Node {}
+var head = nil;
+for (var i = 0; i < 100; i = i + 1) {
+  var node = Node();
+  node.value = i;
+  node.next = head;
+  node.self = node;
+  head = node;
+}
+var sum = 0;
+while (head != nil) {
+  sum = sum + head.value;
+  head = head.next;
+}
+print sum;
+", "4950\n", "", Result::Ok(()))]
+#[case::native_clock("var t = clock(); print t >= 0 and clock() >= t;", RETURN_TRUE, "", Result::Ok(()))]
+#[case::native_print("print clock;", "native fn <clock>\n", "", Result::Ok(()))]
+#[case::native_sleep("print sleep(0);", "nil\n", "", Result::Ok(()))]
+#[case::native_arity("clock(1);", "",
+    "Expected 0 arguments but got 1.\n    clock(1);\n         ^\n[line 1] in script\n",
+    runtime_error("Expected 0 arguments but got 1.", &[(1, 6, None)]))]
+#[case::native_error("sleep(\"a\");", "",
+    "sleep() expects a non-negative number of seconds.\n    sleep(\"a\");\n         ^\n[line 1] in script\n",
+    runtime_error("sleep() expects a non-negative number of seconds.", &[(1, 6, None)]))]
+#[case::trace_nested_calls(
+"fun a() { return nil + 1; }
+fun b() {
+  return a();
+}
+b();", "",
+    "Operands must be numbers.\n    fun a() { return nil + 1; }\n                         ^\n[line 1] in a()\n[line 3] in b()\n[line 5] in script\n",
+    runtime_error("Operands must be numbers.", &[(1, 22, Some("a")), (3, 11, Some("b")), (5, 2, None)]))]
+#[case::trace_method(
+"class A {
+  m() { return this.missing; }
+}
+A().m();", "",
+    "Undefined property 'missing'.\n      m() { return this.missing; }\n                        ^~~~~~~\n[line 2] in m()\n[line 4] in script\n",
+    runtime_error("Undefined property 'missing'.", &[(2, 21, Some("m")), (4, 6, None)]))]
+#[case::multiple_compile_errors(
+"print 1 +;
+var = 2;
+print 3;", "",
+    "[line 1:10] Error: Expect expression\n    print 1 +;\n             ^\n[line 2:5] Error: Expect variable name.\n    var = 2;\n        ^\n",
+    compile_error(&[(1, 10, 9..10, "Expect expression"), (2, 5, 15..16, "Expect variable name.")]))]
+#[case::break_while(
+"var i = 0;
+while (true) {
+  var doubled = i * 2;
+  if (doubled > 4) break;
+  print doubled;
+  i = i + 1;
+}
+print i;", "0\n2\n4\n3\n", "", Result::Ok(()))]
+#[case::continue_for(
+"for (var i = 0; i < 5; i = i + 1) {
+  if (i == 1 or i == 3) continue;
+  { var nested = i; print nested; }
+}", "0\n2\n4\n", "", Result::Ok(()))]
+#[case::break_inner_loop(
+"for (var i = 0; i < 2; i = i + 1) {
+  for (var j = 0; j < 10; j = j + 1) {
+    if (j == 2) break;
+    print i * 10 + j;
+  }
+}", "0\n1\n10\n11\n", "", Result::Ok(()))]
+#[case::break_closes_upvalues(
+"var f;
+for (var i = 0; ; i = i + 1) {
+  var captured = i;
+  fun g() { return captured; }
+  f = g;
+  if (i == 3) break;
+}
+print f();", "3\n", "", Result::Ok(()))]
+#[case::continue_while(
+"var i = 0;
+while (i < 4) {
+  i = i + 1;
+  var skip = i == 2;
+  if (skip) continue;
+  print i;
+}", "1\n3\n4\n", "", Result::Ok(()))]
+#[case::break_outside_loop("break;", "",
+    "[line 1:1] Error: Can't use 'break' outside of a loop.\n    break;\n    ^~~~~\n",
+    compile_error(&[(1, 1, 0..5, "Can't use 'break' outside of a loop.")]))]
+#[case::continue_in_function_in_loop("while (false) { fun f() { continue; } }", "",
+    "[line 1:27] Error: Can't use 'continue' outside of a loop.\n    while (false) { fun f() { continue; } }\n                              ^~~~~~~~\n",
+    compile_error(&[(1, 27, 26..34, "Can't use 'continue' outside of a loop.")]))]
+#[case::list_literal("print [1, \"a\", nil, [true],];", "[1, \"a\", nil, [true]]\n", "", Result::Ok(()))]
+#[case::list_index(
+"var xs = [10, 20, 30];
+print xs[0] + xs[2];
+print xs[-1];
+print xs[1] = 5;
+print xs;", "40\n30\n5\n[10, 5, 30]\n", "", Result::Ok(()))]
+#[case::list_methods(
+"var xs = [];
+xs.push(1);
+xs.push(3);
+xs.insert(1, 2);
+xs.insert(3, 4);
+print xs.len();
+print xs.pop();
+print xs.remove(0);
+print xs;", "4\n4\n1\n[2, 3]\n", "", Result::Ok(()))]
+#[case::list_slice(
+"var xs = [0, 1, 2, 3, 4];
+print xs.slice(1, 3);
+print xs.slice(-2, nil);
+print xs.slice(nil, 100);
+print xs.slice(3, 1);", "[1, 2]\n[3, 4]\n[0, 1, 2, 3, 4]\n[]\n", "", Result::Ok(()))]
+#[case::list_contains_itself("var xs = [1]; xs.push(xs); print xs;", "[1, [...]]\n", "", Result::Ok(()))]
+#[case::list_index_out_of_range("var xs = [1]; print xs[1];", "",
+    "List index out of range.\n    var xs = [1]; print xs[1];\n                          ^\n[line 1] in script\n",
+    runtime_error("List index out of range.", &[(1, 23, None)]))]
+#[case::list_index_not_integer("print [1][0.5];", "",
+    "List index must be an integer.\n    print [1][0.5];\n             ^\n[line 1] in script\n",
+    runtime_error("List index must be an integer.", &[(1, 10, None)]))]
+#[case::index_non_list("print 1[0];", "",
+    "Only lists, maps and strings can be indexed.\n    print 1[0];\n           ^\n[line 1] in script\n",
+    runtime_error("Only lists, maps and strings can be indexed.", &[(1, 8, None)]))]
+#[case::list_pop_empty("[].pop();", "",
+    "Can't pop from an empty list.\n    [].pop();\n          ^\n[line 1] in script\n",
+    runtime_error("Can't pop from an empty list.", &[(1, 7, None)]))]
+#[case::list_undefined_method("[].foo;", "",
+    "Undefined property 'foo'.\n    [].foo;\n       ^~~\n[line 1] in script\n",
+    runtime_error("Undefined property 'foo'.", &[(1, 4, None)]))]
+#[case::unclosed_list("print [1, 2;", "",
+    "[line 1:12] Error: Expect ']' after list items.\n    print [1, 2;\n               ^\n",
+    compile_error(&[(1, 12, 11..12, "Expect ']' after list items.")]))]
+#[case::map_literal(
+    "print {\"a\": 1, 2: [nil], true: false, nil: \"n\",};",
+    "{\"a\": 1, 2: [nil], true: false, nil: \"n\"}\n", "", Result::Ok(()))]
+#[case::map_index(
+"var m = {};
+m[\"a\" + \"b\"] = 1;
+print m[\"ab\"];
+print m[\"ab\"] = 2;
+m[-0] = \"zero\";
+print m[0];
+print m;", "1\n2\n\"zero\"\n{\"ab\": 2, -0: \"zero\"}\n", "", Result::Ok(()))]
+#[case::map_methods(
+"var m = {\"a\": 1, \"b\": 2, \"c\": 3};
+print m.has(\"b\");
+print m.delete(\"b\");
+print m.delete(\"b\");
+print m.has(\"b\");
+m[\"b\"] = 4;
+print m.keys();
+print m.values();
+print m.len();", "true\ntrue\nfalse\nfalse\n[\"a\", \"c\", \"b\"]\n[1, 3, 4]\n3\n", "", Result::Ok(()))]
+#[case::map_contains_itself("var m = {}; m[1] = m; print m;", "{1: {...}}\n", "", Result::Ok(()))]
+#[case::map_undefined_key("var m = {}; print m[\"q\"];", "",
+    "Undefined key \"q\".\n    var m = {}; print m[\"q\"];\n                       ^\n[line 1] in script\n",
+    runtime_error("Undefined key \"q\".", &[(1, 20, None)]))]
+#[case::map_unhashable_key("var m = {}; m[[]] = 1;", "",
+    "Map keys must be strings, numbers, booleans or nil.\n    var m = {}; m[[]] = 1;\n                 ^\n[line 1] in script\n",
+    runtime_error("Map keys must be strings, numbers, booleans or nil.", &[(1, 14, None)]))]
+#[case::map_missing_colon("print {\"a\" 1};", "",
+    "[line 1:12] Error: Expect ':' after map key.\n    print {\"a\" 1};\n               ^\n",
+    compile_error(&[(1, 12, 11..12, "Expect ':' after map key.")]))]
+#[case::for_in_list("for (var x in [1, 2, 3]) print x * 2;", "2\n4\n6\n", "", Result::Ok(()))]
+#[case::for_in_map_keys(
+"var m = {\"a\": 1, \"b\": 2};
+for (key in m) print m[key];", "1\n2\n", "", Result::Ok(()))]
+#[case::for_in_string("for (c in \"añb\") print c;", "\"a\"\n\"ñ\"\n\"b\"\n", "", Result::Ok(()))]
+#[case::for_in_range("for (i in range(-1, 2)) print i; for (i in range(3, 3)) print i;", "-1\n0\n1\n", "", Result::Ok(()))]
+#[case::for_in_break_continue(
+"for (i in range(0, 10)) {
+  if (i == 1) continue;
+  if (i == 3) break;
+  print i;
+}", "0\n2\n", "", Result::Ok(()))]
+#[case::for_in_sees_pushed_items(
+"var xs = [1];
+for (x in xs) { if (x < 3) xs.push(x + 1); print x; }", "1\n2\n3\n", "", Result::Ok(()))]
+#[case::for_in_captures_each_item(
+"var fs = [];
+for (x in [1, 2]) { fun f() { return x; } fs.push(f); }
+print fs[0]();
+print fs[1]();", "1\n2\n", "", Result::Ok(()))]
+#[case::for_in_iterator_protocol(
+"class Countdown {
+  init(n) { this.n = n; }
+  iter() { return this; }
+  next() {
+    if (this.n == 0) return nil;
+    this.n = this.n - 1;
+    return this.n + 1;
+  }
+}
+for (n in Countdown(3)) print n;", "3\n2\n1\n", "", Result::Ok(()))]
+#[case::for_in_iter_returns_list(
+"class Pair {
+  iter() { return [\"a\", \"b\"]; }
+}
+for (x in Pair()) print x;", "\"a\"\n\"b\"\n", "", Result::Ok(()))]
+#[case::for_in_not_iterable("for (x in 1) print x;", "",
+    "Only lists, maps, strings and iterators can be looped over.\n    for (x in 1) print x;\n           ^~\n[line 1] in script\n",
+    runtime_error("Only lists, maps, strings and iterators can be looped over.", &[(1, 8, None)]))]
+#[case::for_in_iterator_without_next(
+"class A { iter() { return this; } }
+for (x in A()) print x;", "",
+    "Iterator has no 'next' method.\n    for (x in A()) print x;\n           ^~\n[line 2] in script\n",
+    runtime_error("Iterator has no 'next' method.", &[(2, 8, None)]))]
+#[case::for_in_error_in_next(
+"class A { next() { return nil + 1; } }
+for (x in A()) print x;", "",
+    "Operands must be numbers.\n    class A { next() { return nil + 1; } }\n                                  ^\n[line 1] in next()\n[line 2] in script\n",
+    runtime_error("Operands must be numbers.", &[(1, 31, Some("next")), (2, 8, None)]))]
+#[case::string_escapes(
+    r#"print "tab\there\n\"quoted\" \\ \u{1F600}";"#,
+    "\"tab\there\n\"quoted\" \\ \u{1F600}\"\n", "", Result::Ok(()))]
+#[case::string_invalid_escape(r#"print "a\qb";"#, "",
+    "[line 1:9] Error: Invalid escape sequence '\\q'.\n    print \"a\\qb\";\n            ^~\n",
+    compile_error(&[(1, 9, 8..10, "Invalid escape sequence '\\q'.")]))]
+#[case::string_invalid_unicode_escape(r#"print "\u{D800}";"#, "",
+    "[line 1:8] Error: Invalid escape sequence '\\u{D800}'.\n    print \"\\u{D800}\";\n           ^~~~~~~~\n",
+    compile_error(&[(1, 8, 7..15, "Invalid escape sequence '\\u{D800}'.")]))]
+#[case::string_len_counts_characters(
+    "print \"naïve 😀\".len();", "7\n", "", Result::Ok(()))]
+#[case::string_index(
+"var s = \"añb\";
+print s[1];
+print s[-1];", "\"ñ\"\n\"b\"\n", "", Result::Ok(()))]
+#[case::string_slice(
+"var s = \"héllo\";
+print s.slice(1, 3);
+print s.slice(-2, nil);
+print s.slice(4, 1);", "\"él\"\n\"lo\"\n\"\"\n", "", Result::Ok(()))]
+#[case::string_index_out_of_range("print \"é\"[1];", "",
+    "String index out of range.\n    print \"é\"[1];\n             ^\n[line 1] in script\n",
+    runtime_error("String index out of range.", &[(1, 10, None)]))]
+#[case::string_set_index("var s = \"a\"; s[0] = \"b\";", "",
+    "Strings are immutable.\n    var s = \"a\"; s[0] = \"b\";\n                  ^\n[line 1] in script\n",
+    runtime_error("Strings are immutable.", &[(1, 15, None)]))]
 fn interpreter(
     #[case] input: &str,
     #[case] expected_output: &str,